@@ -10,28 +10,31 @@ use chunkfs::hashers::Sha256Hasher;
 use chunkfs::Chunker;
 use chunkfs::FileSystem;
 use chunkfs::Hasher;
+use chunkfs::OpenMode;
 
 fn main() -> io::Result<()> {
-    //parametrized_write(FSChunker::new(16384), SimpleHasher)?;
-    //parametrized_write(FSChunker::new(16384), Sha256Hasher::default())?;
+    //parametrized_write(|| FSChunker::new(16384), SimpleHasher)?;
+    //parametrized_write(|| FSChunker::new(16384), Sha256Hasher::default())?;
     println!();
-    //parametrized_write(LeapChunker::default(), SimpleHasher)?;
-    //parametrized_write(LeapChunker::default(), Sha256Hasher::default())?;
-    parametrized_write(RabinChunker::new(), Sha256Hasher::default())
+    //parametrized_write(LeapChunker::default, SimpleHasher)?;
+    //parametrized_write(LeapChunker::default, Sha256Hasher::default())?;
+    parametrized_write(RabinChunker::new, Sha256Hasher::default())
 }
 
 const MB: usize = 1024 * 1024;
 
-fn parametrized_write(
-    chunker: impl Chunker + Debug,
-    hasher: impl Hasher + Debug,
-) -> io::Result<()> {
-    println!("Current chunker: {:?}", chunker);
+fn parametrized_write<C, H>(chunker: impl Fn() -> C, hasher: H) -> io::Result<()>
+where
+    C: Chunker + Debug + Send,
+    H: Hasher + Clone + Debug + Send + 'static,
+    H::Hash: Send + 'static,
+{
+    println!("Current chunker: {:?}", chunker());
     println!("Current hasher: {:?}", hasher);
     let base = HashMap::default();
     let mut fs = FileSystem::new_cdc_only(base, hasher);
 
-    let mut handle = fs.create_file("file".to_string(), chunker, true)?;
+    let mut handle = fs.create_file("file".to_string(), chunker(), true)?;
 
     const MB_COUNT: usize = 1024;
 
@@ -54,7 +57,25 @@ fn parametrized_write(
         speed
     );
 
-    let handle = fs.open_file("file", LeapChunker::default())?;
+    println!(
+        "Hashing: {:.3} s wall time, {:.3} s on all threads",
+        measurements.hash_time().as_secs_f64(),
+        measurements.hash_cpu_time().as_secs_f64()
+    );
+    println!(
+        "Buffer assembly: {:.3} s, copying: {:.3} s, database: {:.3} s",
+        measurements.buffer_time().as_secs_f64(),
+        measurements.copy_time().as_secs_f64(),
+        measurements.insert_time().as_secs_f64()
+    );
+    println!(
+        "Produced {} chunks, {} of {} bytes were duplicates",
+        measurements.chunk_count(),
+        measurements.duplicate_bytes(),
+        measurements.written_bytes()
+    );
+
+    let handle = fs.open_file("file", LeapChunker::default(), OpenMode::Read)?;
     let watch = Instant::now();
     let read = fs.read_file_complete(&handle)?;
     let read_time = watch.elapsed().as_secs_f64();
@@ -63,10 +84,31 @@ fn parametrized_write(
         read_time,
         MB_COUNT as f64 / read_time
     );
+    let read_measurements = handle.read_measurements();
+    println!(
+        "Lookup: {:.3} s, database: {:.3} s, reconstruction: {:.3} s, concatenation: {:.3} s",
+        read_measurements.lookup_time().as_secs_f64(),
+        read_measurements.retrieve_time().as_secs_f64(),
+        read_measurements.reconstruct_time().as_secs_f64(),
+        read_measurements.concat_time().as_secs_f64()
+    );
 
     assert_eq!(read.len(), data.len());
     assert_eq!(read, data);
 
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    fs.set_hash_threads(threads);
+    let mut handle = fs.create_file("pipelined".to_string(), chunker(), true)?;
+    let watch = Instant::now();
+    fs.write_to_file_pipelined(&mut handle, &data)?;
+    fs.close_file(handle)?;
+    let write_time = watch.elapsed().as_secs_f64();
+    println!(
+        "Written {MB_COUNT} MB with pipelined writer and {threads} hashing threads in {:.3} seconds => write speed is {:.3} MB/s",
+        write_time,
+        MB_COUNT as f64 / write_time
+    );
+
     Ok(())
 }
 
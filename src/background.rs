@@ -0,0 +1,112 @@
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::map::Database;
+use crate::scrub::{ScrubBudget, ScrubMeasurements};
+use crate::shared::SharedFileSystem;
+use crate::storage::DataContainer;
+use crate::system::FileSystem;
+use crate::{ChunkHash, Hasher};
+
+/// Policy that decides when the [`BackgroundScrubber`] scrubs the file system.
+///
+/// Chunks written since the last scrub are scrubbed once nothing is written for `idle_time`,
+/// or once there are at least `dirty_bytes` of them, so that scrubbing keeps up with continuous writes.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct ScrubPolicy {
+    /// Time without writes after which the file system is considered idle.
+    pub idle_time: Duration,
+    /// Amount of data (in bytes) written since the last scrub, which is scrubbed even if the file system is not idle.
+    pub dirty_bytes: usize,
+    /// Budget of each incremental scrub. The file system is locked while it runs.
+    pub budget: ScrubBudget,
+    /// How often the policy is checked. At most one incremental scrub runs per interval.
+    pub poll_interval: Duration,
+}
+
+impl Default for ScrubPolicy {
+    fn default() -> Self {
+        Self {
+            idle_time: Duration::from_secs(1),
+            dirty_bytes: 64 * 1024 * 1024,
+            budget: ScrubBudget::Time(Duration::from_millis(5)),
+            poll_interval: Duration::from_millis(10),
+        }
+    }
+}
+
+impl ScrubPolicy {
+    fn should_scrub(&self, idle_time: Duration, pending_data: usize) -> bool {
+        pending_data > 0 && (idle_time >= self.idle_time || pending_data >= self.dirty_bytes)
+    }
+}
+
+/// Worker thread that [incrementally scrubs][FileSystem::scrub_incremental] a [`SharedFileSystem`]
+/// according to a [`ScrubPolicy`], while other threads keep writing to it.
+///
+/// The policy is checked without locking the file system, which is only locked while a single scrub runs,
+/// so writes wait for at most one scrub budget.
+/// Created by [`SharedFileSystem::spawn_scrubber`].
+pub struct BackgroundScrubber {
+    stop_sender: Sender<()>,
+    thread: JoinHandle<io::Result<ScrubMeasurements>>,
+}
+
+impl BackgroundScrubber {
+    /// Stops the worker after the scrub it is running, if any, and waits for it to finish.
+    /// Chunks that are not scrubbed yet stay pending, and can be scrubbed later.
+    ///
+    /// Returns measurements of all scrubs made by the worker, or the error that stopped the worker.
+    ///
+    /// # Panics
+    /// Panics if the worker panicked.
+    pub fn stop(self) -> io::Result<ScrubMeasurements> {
+        // the worker may have already stopped because of an error
+        let _ = self.stop_sender.send(());
+        self.thread.join().unwrap()
+    }
+
+    /// Returns `true` if the worker has stopped because of an error.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+}
+
+impl<B, H, Hash, K> SharedFileSystem<B, H, Hash, K>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+    FileSystem<B, H, Hash, K>: Send + 'static,
+{
+    /// Starts a [`BackgroundScrubber`] that scrubs the file system according to the `policy`.
+    ///
+    /// The scrubber must support [scrubbing single chunks][crate::Scrub::scrub_chunk],
+    /// otherwise the worker stops with `ErrorKind::Unsupported` once it tries to scrub.
+    pub fn spawn_scrubber(&self, policy: ScrubPolicy) -> BackgroundScrubber {
+        let fs = self.clone();
+        let activity = self.lock().write_activity();
+        let (stop_sender, stop_receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut measurements = ScrubMeasurements::default();
+            while let Err(RecvTimeoutError::Timeout) =
+                stop_receiver.recv_timeout(policy.poll_interval)
+            {
+                // the policy is checked without locking the file system, so that writers are not stalled by polling
+                if policy.should_scrub(activity.idle_time(), activity.pending_bytes()) {
+                    measurements += fs.lock().scrub_incremental(policy.budget)?;
+                }
+            }
+            Ok(measurements)
+        });
+
+        BackgroundScrubber {
+            stop_sender,
+            thread,
+        }
+    }
+}
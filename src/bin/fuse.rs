@@ -0,0 +1,26 @@
+extern crate chunkfs;
+
+use std::collections::HashMap;
+use std::env;
+use std::io;
+
+use chunkfs::chunkers::LeapChunker;
+use chunkfs::fuse;
+use chunkfs::hashers::Sha256Hasher;
+use chunkfs::FileSystem;
+
+/// Mounts an empty in-memory chunkfs at the given directory.
+/// Prints accumulated write measurements once the file system is unmounted.
+fn main() -> io::Result<()> {
+    let Some(mountpoint) = env::args().nth(1) else {
+        eprintln!("Usage: chunkfs-fuse <mountpoint>");
+        return Ok(());
+    };
+
+    let fs = FileSystem::new_cdc_only(HashMap::default(), Sha256Hasher::default());
+    let measurements = fuse::mount(fs, LeapChunker::default, mountpoint)?;
+
+    println!("Chunking time: {:?}", measurements.chunk_time());
+    println!("Hashing time: {:?}", measurements.hash_time());
+    Ok(())
+}
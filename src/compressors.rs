@@ -0,0 +1,234 @@
+use std::io;
+use std::io::ErrorKind;
+
+use crate::Compressor;
+
+/// Pure Rust LZ77 compressor without any dependencies.
+///
+/// Compressed data starts with the length of the original data, followed by sequences of literal bytes,
+/// each of which is followed by a match, i.e., a copy of earlier data at some offset, except for the last one.
+/// Lengths and offsets are written as LEB128 varints.
+#[derive(Debug, Default, Clone)]
+pub struct SimpleCompressor;
+
+/// Matches shorter than this are written as literals.
+const MIN_MATCH: usize = 4;
+
+/// Maximum distance to the data that is copied by a match.
+const MAX_OFFSET: usize = 65535;
+
+/// Amount of bits in the hashes of 4-byte sequences that are used to find matches.
+const HASH_BITS: u32 = 14;
+
+impl Compressor for SimpleCompressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::with_capacity(data.len() / 2 + 8);
+        write_varint(&mut compressed, data.len());
+
+        // positions of the last occurrences of 4-byte sequences, plus one, so that zero means no occurrence
+        let mut table = vec![0usize; 1 << HASH_BITS];
+        let mut literals_start = 0;
+        let mut position = 0;
+        while position + MIN_MATCH <= data.len() {
+            let sequence = &data[position..position + MIN_MATCH];
+            let hash = (u32::from_le_bytes(sequence.try_into().unwrap()).wrapping_mul(2654435761)
+                >> (32 - HASH_BITS)) as usize;
+            let candidate = std::mem::replace(&mut table[hash], position + 1);
+
+            if candidate > 0
+                && position - (candidate - 1) <= MAX_OFFSET
+                && &data[candidate - 1..candidate - 1 + MIN_MATCH] == sequence
+            {
+                let start = candidate - 1;
+                let length = data[position..]
+                    .iter()
+                    .zip(&data[start..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                write_varint(&mut compressed, position - literals_start);
+                compressed.extend_from_slice(&data[literals_start..position]);
+                write_varint(&mut compressed, length - MIN_MATCH);
+                write_varint(&mut compressed, position - start);
+
+                position += length;
+                literals_start = position;
+            } else {
+                position += 1;
+            }
+        }
+
+        if literals_start < data.len() {
+            write_varint(&mut compressed, data.len() - literals_start);
+            compressed.extend_from_slice(&data[literals_start..]);
+        }
+        compressed
+    }
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut position = 0;
+        let length = read_varint(data, &mut position)?;
+
+        // the length is not trusted when allocating, as the data may be corrupted
+        let mut decompressed = Vec::with_capacity(length.min(data.len() * 4));
+        while decompressed.len() < length {
+            let literals = read_varint(data, &mut position)?;
+            let literals = data
+                .get(position..position.saturating_add(literals))
+                .filter(|literals| decompressed.len() + literals.len() <= length)
+                .ok_or(ErrorKind::InvalidData)?;
+            decompressed.extend_from_slice(literals);
+            position += literals.len();
+            if decompressed.len() == length {
+                break;
+            }
+
+            let match_length = read_varint(data, &mut position)?.saturating_add(MIN_MATCH);
+            let offset = read_varint(data, &mut position)?;
+            if offset == 0
+                || offset > decompressed.len()
+                || decompressed.len().saturating_add(match_length) > length
+            {
+                return Err(ErrorKind::InvalidData.into());
+            }
+
+            // the copied data may overlap with the data that is being written
+            let start = decompressed.len() - offset;
+            for i in start..start + match_length {
+                decompressed.push(decompressed[i]);
+            }
+        }
+
+        match position == data.len() {
+            true => Ok(decompressed),
+            false => Err(ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn read_varint(data: &[u8], position: &mut usize) -> io::Result<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = *data.get(*position).ok_or(ErrorKind::InvalidData)?;
+        *position += 1;
+        value |= ((byte & 0x7f) as usize)
+            .checked_shl(shift)
+            .ok_or(ErrorKind::InvalidData)?;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(ErrorKind::InvalidData.into())
+}
+
+/// LZ4 compressor, provided by the `lz4_flex` crate.
+#[cfg(feature = "compressors")]
+#[derive(Debug, Default, Clone)]
+pub struct Lz4Compressor;
+
+#[cfg(feature = "compressors")]
+impl Compressor for Lz4Compressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        lz4_flex::compress_prepend_size(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        lz4_flex::decompress_size_prepended(data)
+            .map_err(|error| io::Error::new(ErrorKind::InvalidData, error))
+    }
+}
+
+/// Zstandard compressor with the given compression level, provided by the `zstd` crate.
+#[cfg(feature = "compressors")]
+#[derive(Debug, Clone)]
+pub struct ZstdCompressor {
+    level: i32,
+}
+
+#[cfg(feature = "compressors")]
+impl ZstdCompressor {
+    pub fn new(level: i32) -> Self {
+        Self { level }
+    }
+}
+
+#[cfg(feature = "compressors")]
+impl Default for ZstdCompressor {
+    fn default() -> Self {
+        Self::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+#[cfg(feature = "compressors")]
+impl Compressor for ZstdCompressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        // compressing from memory into memory can't fail
+        zstd::bulk::compress(data, self.level).unwrap()
+    }
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::stream::decode_all(data)
+            .map_err(|error| io::Error::new(ErrorKind::InvalidData, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use crate::compressors::SimpleCompressor;
+    #[cfg(feature = "compressors")]
+    use crate::compressors::{Lz4Compressor, ZstdCompressor};
+    use crate::Compressor;
+
+    fn samples() -> Vec<Vec<u8>> {
+        vec![
+            vec![],
+            vec![7],
+            vec![1, 2, 3],
+            vec![0; 100_000],
+            (0..100_000).map(|i| (i % 251) as u8).collect(),
+            (0..100_000u64)
+                .map(|i| (i.wrapping_mul(6364136223846793005) >> 56) as u8)
+                .collect(),
+            b"abcabcabcabcabcabd".repeat(50),
+        ]
+    }
+
+    fn round_trip(compressor: &impl Compressor) {
+        for sample in samples() {
+            let compressed = compressor.compress(&sample);
+            assert_eq!(compressor.decompress(&compressed).unwrap(), sample);
+        }
+    }
+
+    #[test]
+    fn simple_compressor_round_trip() {
+        round_trip(&SimpleCompressor);
+        assert!(SimpleCompressor.compress(&[0; 100_000]).len() < 100);
+    }
+
+    #[cfg(feature = "compressors")]
+    #[test]
+    fn lz4_and_zstd_round_trip() {
+        round_trip(&Lz4Compressor);
+        round_trip(&ZstdCompressor::default());
+    }
+
+    #[test]
+    fn corrupted_data_is_rejected() {
+        let compressed = SimpleCompressor.compress(&b"abcabcabcabcabcabd".repeat(50));
+        for len in 1..compressed.len() {
+            let error = SimpleCompressor.decompress(&compressed[..len]).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
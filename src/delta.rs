@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::time::Instant;
+
+use crate::compressors::{read_varint, write_varint};
+use crate::map::Database;
+use crate::scrub::{Scrub, ScrubMeasurements};
+use crate::storage::{Data, DataContainer};
+use crate::ChunkHash;
+
+/// Amount of features computed for each chunk.
+const FEATURES: usize = 12;
+
+/// Amount of features combined into a single super-feature.
+const FEATURES_PER_SUPER_FEATURE: usize = 4;
+
+const SUPER_FEATURES: usize = FEATURES / FEATURES_PER_SUPER_FEATURE;
+
+/// Chunks shorter than this are left in the database, as their features are not representative.
+const MIN_CHUNK_SIZE: usize = 64;
+
+/// Copies from the base shorter than this are written to the delta as literals.
+const MIN_COPY: usize = 8;
+
+/// Amount of bits in the hashes of base windows that are used to find copies.
+const INDEX_BITS: u32 = 16;
+
+/// Random values for the gear hash, which is used as a rolling fingerprint of the last 64 bytes.
+const GEAR: [u64; 256] = gear_table();
+
+/// Scrubber that stores chunks which are similar, but not identical, to other chunks as deltas.
+///
+/// Similar chunks are found using super-feature sketches: each chunk gets twelve features,
+/// which are maximal values of different transformations of the rolling fingerprint over the chunk,
+/// and every four of them are combined into a super-feature.
+/// Chunks that share a super-feature are likely to have a lot of data in common.
+///
+/// A chunk that is not similar to any of the previously scrubbed ones is moved to the target map as a base.
+/// A chunk that is similar to a base is replaced with a delta, i.e., instructions on how to restore it
+/// by copying parts of the base, if the delta is less than half the chunk's size.
+/// Bases are remembered between scrubs, but not when the file system is reopened.
+///
+/// Keys of the target map are consecutive numbers, skipping the ones that are already present in it.
+pub struct DeltaScrubber<Key> {
+    /// Target map keys of the bases, indexed by their super-features.
+    bases: HashMap<u64, Key>,
+    next_key: u64,
+}
+
+impl<Key> DeltaScrubber<Key> {
+    pub fn new() -> Self {
+        Self {
+            bases: HashMap::new(),
+            next_key: 0,
+        }
+    }
+}
+
+impl<Key> Default for DeltaScrubber<Key> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Key: TryFrom<u64>> DeltaScrubber<Key> {
+    /// Returns the next key that is not present in the `target_map`.
+    ///
+    /// Returns `ErrorKind::Other`, if all keys that fit into `Key` are used.
+    fn next_key(&mut self, target_map: &dyn Database<Key, Vec<u8>>) -> io::Result<Key> {
+        loop {
+            let key =
+                Key::try_from(self.next_key).map_err(|_| io::Error::from(ErrorKind::Other))?;
+            self.next_key += 1;
+            if !target_map.contains(&key) {
+                return Ok(key);
+            }
+        }
+    }
+}
+
+impl<Hash: ChunkHash, B, Key> Scrub<Hash, B, Key> for DeltaScrubber<Key>
+where
+    B: Database<Hash, DataContainer<Key>>,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<Key>)>,
+    Key: TryFrom<u64> + Clone,
+{
+    fn scrub<'a>(
+        &mut self,
+        database: &mut B,
+        target_map: &mut Box<dyn Database<Key, Vec<u8>> + Send>,
+    ) -> io::Result<ScrubMeasurements>
+    where
+        Hash: 'a,
+        Key: 'a,
+    {
+        let start = Instant::now();
+        let mut measurements = ScrubMeasurements::default();
+        database.for_each_mut(|_, container| {
+            self.scrub_chunk(container, target_map, &mut measurements)
+        })?;
+
+        measurements.running_time = start.elapsed();
+        Ok(measurements)
+    }
+
+    fn scrub_chunk(
+        &mut self,
+        container: &mut DataContainer<Key>,
+        target_map: &mut Box<dyn Database<Key, Vec<u8>> + Send>,
+        measurements: &mut ScrubMeasurements,
+    ) -> io::Result<()> {
+        let Data::Chunk(chunk) = container.extract_mut() else {
+            return Ok(());
+        };
+        if chunk.len() < MIN_CHUNK_SIZE {
+            measurements.data_left += chunk.len();
+            return Ok(());
+        }
+        measurements.processed_data += chunk.len();
+
+        let sketch = sketch(chunk);
+        // bases of released chunks may have been removed from the target map
+        let base_key = sketch.iter().find_map(|feature| {
+            self.bases
+                .get(feature)
+                .filter(|key| target_map.contains(key))
+        });
+        if let Some(base_key) = base_key {
+            let base_key = base_key.clone();
+            let delta = encode_delta(&target_map.get(&base_key)?, chunk);
+            if delta.len() < chunk.len() / 2 {
+                measurements.saved_data += chunk.len() - delta.len();
+                let delta_key = self.next_key(target_map.as_ref())?;
+                target_map.insert(delta_key.clone(), delta)?;
+                container.make_delta(vec![base_key], vec![delta_key]);
+                return Ok(());
+            }
+        }
+
+        let key = self.next_key(target_map.as_ref())?;
+        target_map.insert(key.clone(), std::mem::take(chunk))?;
+        container.make_target(vec![key.clone()]);
+        for feature in sketch {
+            let base = self.bases.entry(feature).or_insert_with(|| key.clone());
+            if !target_map.contains(base) {
+                *base = key.clone();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Computes super-features of the `data`.
+fn sketch(data: &[u8]) -> [u64; SUPER_FEATURES] {
+    let mut features = [0u64; FEATURES];
+    let mut fingerprint = 0u64;
+    for &byte in data {
+        fingerprint = (fingerprint << 1).wrapping_add(GEAR[byte as usize]);
+        for (i, feature) in features.iter_mut().enumerate() {
+            // each feature uses its own linear transformation, with parameters taken from the gear table
+            let transformed = fingerprint
+                .wrapping_mul(GEAR[i] | 1)
+                .wrapping_add(GEAR[FEATURES + i]);
+            *feature = (*feature).max(transformed);
+        }
+    }
+
+    let mut super_features = [0u64; SUPER_FEATURES];
+    for (i, group) in features.chunks(FEATURES_PER_SUPER_FEATURE).enumerate() {
+        // the index is mixed in, so that super-features made of different features don't match
+        super_features[i] = group.iter().fold(i as u64, |hash, feature| {
+            (hash.rotate_left(23) ^ feature).wrapping_mul(0x9E3779B97F4A7C15)
+        });
+    }
+    super_features
+}
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so that the table is the same on every run
+    let mut table = [0; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D049BB133111EB);
+        table[i] = value ^ (value >> 31);
+        i += 1;
+    }
+    table
+}
+
+fn hash_window(window: &[u8]) -> usize {
+    let value = u64::from_le_bytes(window.try_into().unwrap());
+    (value.wrapping_mul(0x9E3779B97F4A7C15) >> (64 - INDEX_BITS)) as usize
+}
+
+/// Encodes the `target` as a delta against the `base`.
+///
+/// The delta starts with the length of the target, followed by sequences of literal bytes,
+/// each of which is followed by a copy of the base's data at some offset, except for the last one.
+/// Lengths and offsets are written as LEB128 varints.
+pub(crate) fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_varint(&mut delta, target.len());
+
+    // positions of the last occurrences of windows in the base, plus one, so that zero means no occurrence
+    let mut index = vec![0usize; 1 << INDEX_BITS];
+    for (position, window) in base.windows(MIN_COPY).enumerate() {
+        index[hash_window(window)] = position + 1;
+    }
+
+    let mut literals_start = 0;
+    let mut position = 0;
+    while position + MIN_COPY <= target.len() {
+        let window = &target[position..position + MIN_COPY];
+        let candidate = index[hash_window(window)];
+        if candidate == 0 || &base[candidate - 1..candidate - 1 + MIN_COPY] != window {
+            position += 1;
+            continue;
+        }
+
+        // the copy is extended backwards over the pending literals, and forwards as far as the data matches
+        let mut start = candidate - 1;
+        let mut copy_start = position;
+        while start > 0 && copy_start > literals_start && base[start - 1] == target[copy_start - 1]
+        {
+            start -= 1;
+            copy_start -= 1;
+        }
+        let length = target[copy_start..]
+            .iter()
+            .zip(&base[start..])
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        write_varint(&mut delta, copy_start - literals_start);
+        delta.extend_from_slice(&target[literals_start..copy_start]);
+        write_varint(&mut delta, length - MIN_COPY);
+        write_varint(&mut delta, start);
+
+        position = copy_start + length;
+        literals_start = position;
+    }
+
+    if literals_start < target.len() {
+        write_varint(&mut delta, target.len() - literals_start);
+        delta.extend_from_slice(&target[literals_start..]);
+    }
+    delta
+}
+
+/// Restores the data encoded by [`encode_delta`] from the `base` and the `delta`.
+///
+/// Returns `ErrorKind::InvalidData`, if the delta is corrupted or doesn't belong to the base.
+pub(crate) fn apply_delta(base: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position)?;
+
+    // the length is not trusted when allocating, as the delta may be corrupted
+    let mut target = Vec::with_capacity(length.min(base.len() + delta.len()));
+    while target.len() < length {
+        let literals = read_varint(delta, &mut position)?;
+        let literals = delta
+            .get(position..position.saturating_add(literals))
+            .filter(|literals| target.len() + literals.len() <= length)
+            .ok_or(ErrorKind::InvalidData)?;
+        target.extend_from_slice(literals);
+        position += literals.len();
+        if target.len() == length {
+            break;
+        }
+
+        let copy_length = read_varint(delta, &mut position)?.saturating_add(MIN_COPY);
+        let offset = read_varint(delta, &mut position)?;
+        let copied = base
+            .get(offset..offset.saturating_add(copy_length))
+            .filter(|copied| target.len() + copied.len() <= length)
+            .ok_or(ErrorKind::InvalidData)?;
+        target.extend_from_slice(copied);
+    }
+
+    match position == delta.len() {
+        true => Ok(target),
+        false => Err(ErrorKind::InvalidData.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::ErrorKind;
+
+    use crate::common::random_data;
+    use crate::storage::{Data, DataContainer};
+    use crate::{Database, Scrub};
+
+    use super::{apply_delta, encode_delta, sketch, DeltaScrubber};
+
+    /// Returns a copy of the `data` with a few bytes changed, inserted and removed.
+    fn edited(data: &[u8]) -> Vec<u8> {
+        let mut edited = data.to_vec();
+        edited[100] ^= 0xff;
+        edited.splice(2000..2000, [1, 2, 3]);
+        edited.drain(3000..3010);
+        edited
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let base = random_data(8192, 1);
+        for target in [
+            edited(&base),
+            base.clone(),
+            random_data(8192, 2),
+            vec![],
+            base[..5].to_vec(),
+        ] {
+            let delta = encode_delta(&base, &target);
+            assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+        }
+        assert!(encode_delta(&base, &edited(&base)).len() < 100);
+    }
+
+    #[test]
+    fn corrupted_delta_is_rejected() {
+        let base = random_data(8192, 1);
+        let delta = encode_delta(&base, &edited(&base));
+        for len in 1..delta.len() {
+            let error = apply_delta(&base, &delta[..len]).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+        let error = apply_delta(&base[..4000], &delta).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn similar_chunks_share_super_features() {
+        let data = random_data(8192, 1);
+        let similar = sketch(&data)
+            .iter()
+            .zip(sketch(&edited(&data)))
+            .any(|(a, b)| *a == b);
+        assert!(similar);
+
+        let different = sketch(&data)
+            .iter()
+            .zip(sketch(&random_data(8192, 2)))
+            .any(|(a, b)| *a == b);
+        assert!(!different);
+    }
+
+    #[test]
+    fn similar_chunks_are_stored_as_deltas() {
+        let original = random_data(8192, 1);
+        let similar = edited(&original);
+        let different = random_data(8192, 2);
+
+        let mut database: HashMap<u8, DataContainer<u64>> = HashMap::new();
+        database.insert(0, DataContainer::from(original.clone()));
+        database.insert(1, DataContainer::from(similar.clone()));
+        database.insert(2, DataContainer::from(different.clone()));
+        database.insert(3, DataContainer::from(vec![1; 10]));
+        let mut target_map: Box<dyn Database<u64, Vec<u8>> + Send> = Box::new(HashMap::new());
+        target_map.insert(0, vec![]).unwrap();
+
+        let measurements = DeltaScrubber::new()
+            .scrub(&mut database, &mut target_map)
+            .unwrap();
+        assert_eq!(
+            measurements.processed_data,
+            original.len() + similar.len() + different.len()
+        );
+        assert_eq!(measurements.data_left, 10);
+        assert!(measurements.saved_data > 8000);
+
+        let deltas = database
+            .values()
+            .filter(|container| matches!(container.extract(), Data::DeltaChunk { .. }))
+            .count();
+        assert_eq!(deltas, 1);
+        assert!(matches!(database[&3].extract(), Data::Chunk(_)));
+        // the key that was already present is not overwritten
+        assert_eq!(target_map.get(&0).unwrap(), vec![]);
+
+        for (key, data) in [(0, original), (1, similar), (2, different)] {
+            let restored = match database[&key].extract() {
+                Data::TargetChunk(keys) => target_map.get_multi(keys).unwrap().concat(),
+                Data::DeltaChunk { base, delta } => apply_delta(
+                    &target_map.get_multi(base).unwrap().concat(),
+                    &target_map.get_multi(delta).unwrap().concat(),
+                )
+                .unwrap(),
+                Data::Chunk(_) => panic!("expected a scrubbed chunk"),
+            };
+            assert_eq!(restored, data);
+        }
+    }
+}
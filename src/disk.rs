@@ -0,0 +1,955 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher as _;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use crate::map::Database;
+use crate::storage::{Data, DataContainer};
+use crate::ChunkHash;
+
+/// Name of the file containing the index of a [`DiskDatabase`].
+const INDEX_FILE: &str = "index";
+
+/// Default maximum size of a single container file, in bytes.
+const DEFAULT_CONTAINER_SIZE: u64 = 64 * 1024 * 1024;
+
+const INSERT_RECORD: u8 = 0;
+const REMOVE_RECORD: u8 = 1;
+
+const CHUNK_TAG: u8 = 0;
+const TARGET_CHUNK_TAG: u8 = 1;
+const DELTA_CHUNK_TAG: u8 = 2;
+
+/// Conversion of hashes and keys to and from bytes, used to store them on disk.
+pub trait Encode: Sized {
+    /// Returns byte representation of the value.
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// Restores the value from bytes produced by [`to_bytes`][Encode::to_bytes].
+    ///
+    /// # Errors
+    /// Should return [ErrorKind::InvalidData], if the bytes do not represent a valid value.
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self>;
+}
+
+/// Location of a stored container in the container files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    container: u32,
+    offset: u64,
+    length: u64,
+}
+
+/// Persistent [`Database`] that stores [`DataContainers`][DataContainer] on disk.
+///
+/// Containers are appended to container files of limited size, located in the database directory.
+/// Their locations are kept in memory and recorded in an append-only index file,
+/// which is read when the database is [opened][DiskDatabase::open] again.
+/// Every [`get`][Database::get] of a container that is not loaded by the iterator opens its container file again.
+///
+/// Containers are never compacted: overwritten containers, including the ones rewritten by a scrubber,
+/// and removed containers stay in the container files and keep taking up disk space.
+///
+/// # Iteration
+/// [`for_each_mut`][Database::for_each_mut] holds only one container in memory at a time
+/// and writes it back right after it is changed, so it should be used for passes over the whole database.
+///
+/// Iterating over `&mut DiskDatabase` reads every container from disk when the iterator reaches it.
+/// As all mutable references yielded by the iterator may be held at once, the containers it reached
+/// stay in memory while the database is borrowed by the iterator. They are written back, if changed, and released
+/// by the next call that takes `&mut self`, including [`flush`][Database::flush], which is also done
+/// when the database is dropped. Containers that could not be read are skipped,
+/// and the error is returned by the next [`flush`][Database::flush].
+pub struct DiskDatabase<Hash, K>
+where
+    Hash: ChunkHash + Encode,
+    K: Encode,
+{
+    path: PathBuf,
+    index: HashMap<Hash, Location>,
+    index_file: File,
+    container: File,
+    container_id: u32,
+    container_size: u64,
+    max_container_size: u64,
+    /// Containers reached by the last iterator, which are written back by the next call that takes `&mut self`.
+    loaded: Vec<Loaded<Hash, K>>,
+    /// Positions of the containers in `loaded`.
+    positions: HashMap<Hash, usize>,
+    /// Error that occurred in a method which can't return it, reported by the next [`flush`][Database::flush].
+    deferred_error: Option<io::Error>,
+    _keys: PhantomData<K>,
+}
+
+impl<Hash, K> DiskDatabase<Hash, K>
+where
+    Hash: ChunkHash + Encode,
+    K: Encode,
+{
+    /// Creates a new database in the directory at the given `path`, creating the directory if necessary.
+    ///
+    /// Returns `ErrorKind::AlreadyExists`, if the directory already contains a database.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+
+        let index_file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(path.join(INDEX_FILE))?;
+        Self::with_index(path, index_file, HashMap::new())
+    }
+
+    /// Opens an existing database in the directory at the given `path`, reading its index.
+    ///
+    /// An incomplete record at the end of the index, e.g. left by a crash, is ignored.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let mut bytes = vec![];
+        File::open(path.join(INDEX_FILE))?.read_to_end(&mut bytes)?;
+        let index = read_index(&bytes)?;
+
+        let index_file = OpenOptions::new()
+            .append(true)
+            .open(path.join(INDEX_FILE))?;
+        Self::with_index(path, index_file, index)
+    }
+
+    fn with_index(
+        path: &Path,
+        index_file: File,
+        index: HashMap<Hash, Location>,
+    ) -> io::Result<Self> {
+        let container_id = index
+            .values()
+            .map(|location| location.container)
+            .max()
+            .unwrap_or(0);
+        let container = open_container(path, container_id)?;
+        let container_size = container.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            index,
+            index_file,
+            container,
+            container_id,
+            container_size,
+            max_container_size: DEFAULT_CONTAINER_SIZE,
+            loaded: vec![],
+            positions: HashMap::new(),
+            deferred_error: None,
+            _keys: PhantomData,
+        })
+    }
+
+    /// Sets the size after which a new container file is started.
+    pub fn with_container_size(mut self, size: u64) -> Self {
+        self.max_container_size = size;
+        self
+    }
+
+    /// Returns the amount of containers stored in the database.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns `true` if the database contains no containers.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Appends encoded container to the current container file and records its location in the index.
+    fn append(&mut self, key: &Hash, record: &[u8]) -> io::Result<()> {
+        if self.container_size > 0
+            && self.container_size + record.len() as u64 > self.max_container_size
+        {
+            self.container_id += 1;
+            self.container = open_container(&self.path, self.container_id)?;
+            self.container_size = 0;
+        }
+
+        let location = Location {
+            container: self.container_id,
+            offset: self.container_size,
+            length: record.len() as u64,
+        };
+        self.container.write_all(record)?;
+        self.container_size += record.len() as u64;
+
+        let mut entry = vec![];
+        write_index_entry(&mut entry, key, location);
+        self.index_file.write_all(&entry)?;
+
+        self.index.insert(key.clone(), location);
+        Ok(())
+    }
+
+    /// Appends chunks to the container files and records their locations in the index.
+    ///
+    /// Records of all chunks are assembled in a single buffer for each container file,
+    /// which is written at once, along with the index entries.
+    fn append_chunks(&mut self, pairs: Vec<(Hash, &[u8])>) -> io::Result<()> {
+        let mut records = vec![];
+        let mut entries = vec![];
+        let mut locations = Vec::with_capacity(pairs.len());
+        for (key, chunk) in pairs {
+            let length = chunk.len() as u64 + 1;
+            if self.container_size > 0 && self.container_size + length > self.max_container_size {
+                self.container.write_all(&records)?;
+                records.clear();
+                self.container_id += 1;
+                self.container = open_container(&self.path, self.container_id)?;
+                self.container_size = 0;
+            }
+
+            let location = Location {
+                container: self.container_id,
+                offset: self.container_size,
+                length,
+            };
+            records.push(CHUNK_TAG);
+            records.extend_from_slice(chunk);
+            self.container_size += length;
+
+            write_index_entry(&mut entries, &key, location);
+            locations.push((key, location));
+        }
+        self.container.write_all(&records)?;
+        self.index_file.write_all(&entries)?;
+
+        self.index.extend(locations);
+        Ok(())
+    }
+
+    /// Forgets the container loaded by the iterator, so that it is not written back.
+    fn unload(&mut self, key: &Hash) {
+        if let Some(position) = self.positions.remove(key) {
+            self.loaded[position].container = None;
+        }
+    }
+
+    /// Writes back the containers that were changed through the iterator.
+    fn write_back(&mut self) -> io::Result<()> {
+        self.positions.clear();
+        for loaded in std::mem::take(&mut self.loaded) {
+            if let Some(container) = loaded.container {
+                let record = encode(&container);
+                if fingerprint(&record) != loaded.fingerprint {
+                    self.append(&loaded.hash, &record)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back the containers that were changed through the iterator and syncs files to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.write_back()?;
+
+        self.container.sync_data()?;
+        self.index_file.sync_data()?;
+
+        match self.deferred_error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<Hash, K> Database<Hash, DataContainer<K>> for DiskDatabase<Hash, K>
+where
+    Hash: ChunkHash + Encode,
+    K: Encode + Clone,
+{
+    fn insert(&mut self, key: Hash, value: DataContainer<K>) -> io::Result<()> {
+        self.write_back()?;
+        self.append(&key, &encode(&value))
+    }
+
+    /// Writes chunks directly from the borrowed data, without creating a container for each of them.
+    fn insert_multi_borrowed(&mut self, pairs: Vec<(Hash, &[u8])>) -> io::Result<()> {
+        self.write_back()?;
+        self.append_chunks(pairs)
+    }
+
+    fn get(&self, key: &Hash) -> io::Result<DataContainer<K>> {
+        let loaded = self
+            .positions
+            .get(key)
+            .map(|position| &self.loaded[*position]);
+        if let Some(container) = loaded.and_then(|loaded| loaded.container.as_ref()) {
+            return Ok(container.clone());
+        }
+
+        let location = self.index.get(key).ok_or(ErrorKind::NotFound)?;
+        let mut file = File::open(container_path(&self.path, location.container))?;
+        decode(&read_record(&mut file, *location)?)
+    }
+
+    fn remove(&mut self, key: &Hash) {
+        self.unload(key);
+        if let Err(error) = self.write_back() {
+            self.deferred_error.get_or_insert(error);
+        }
+        if self.index.remove(key).is_none() {
+            return;
+        }
+
+        let mut entry = vec![REMOVE_RECORD];
+        write_bytes(&mut entry, &key.to_bytes());
+        if let Err(error) = self.index_file.write_all(&entry) {
+            self.deferred_error.get_or_insert(error);
+        }
+    }
+
+    fn contains(&self, key: &Hash) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Reads containers one by one, in the order they are stored in, reusing the open container file,
+    /// and writes every changed container back right away, so only one of them is held in memory at a time.
+    fn for_each_mut<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        Self: Sized,
+        F: FnMut(&Hash, &mut DataContainer<K>) -> io::Result<()>,
+        for<'a> &'a mut Self: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+    {
+        self.write_back()?;
+
+        let mut locations = self
+            .index
+            .iter()
+            .map(|(hash, location)| (hash.clone(), *location))
+            .collect::<Vec<_>>();
+        locations.sort_by_key(|(_, location)| (location.container, location.offset));
+
+        let mut file: Option<(u32, File)> = None;
+        for (hash, location) in locations {
+            let file = match &mut file {
+                Some((id, file)) if *id == location.container => file,
+                file => {
+                    let opened = File::open(container_path(&self.path, location.container))?;
+                    &mut file.insert((location.container, opened)).1
+                }
+            };
+
+            let record = read_record(file, location)?;
+            let mut container = decode(&record)?;
+            f(&hash, &mut container)?;
+
+            let changed = encode(&container);
+            if changed != record {
+                self.append(&hash, &changed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back the containers that were changed through the iterator and syncs files to disk.
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+}
+
+impl<'a, Hash, K> IntoIterator for &'a mut DiskDatabase<Hash, K>
+where
+    Hash: ChunkHash + Encode,
+    K: Encode,
+{
+    type Item = (&'a Hash, &'a mut DataContainer<K>);
+    type IntoIter = IterMut<'a, Hash, K>;
+
+    /// Containers changed through the previous iterator are written back first.
+    fn into_iter(self) -> Self::IntoIter {
+        if let Err(error) = self.write_back() {
+            self.deferred_error.get_or_insert(error);
+        }
+
+        self.loaded = self
+            .index
+            .iter()
+            .map(|(hash, location)| Loaded {
+                hash: hash.clone(),
+                location: *location,
+                container: None,
+                fingerprint: 0,
+            })
+            .collect();
+        self.positions = self
+            .loaded
+            .iter()
+            .enumerate()
+            .map(|(position, loaded)| (loaded.hash.clone(), position))
+            .collect();
+
+        IterMut {
+            path: &self.path,
+            loaded: self.loaded.iter_mut(),
+            deferred_error: &mut self.deferred_error,
+        }
+    }
+}
+
+/// Container reached by the iterator of a [`DiskDatabase`],
+/// along with the fingerprint of its encoded form at the time of reading.
+struct Loaded<Hash, K> {
+    hash: Hash,
+    location: Location,
+    container: Option<DataContainer<K>>,
+    fingerprint: u64,
+}
+
+/// Iterator over `&mut DiskDatabase`, which reads every container from disk when it is reached.
+pub struct IterMut<'a, Hash, K> {
+    path: &'a Path,
+    loaded: std::slice::IterMut<'a, Loaded<Hash, K>>,
+    deferred_error: &'a mut Option<io::Error>,
+}
+
+impl<'a, Hash, K: Encode> Iterator for IterMut<'a, Hash, K> {
+    type Item = (&'a Hash, &'a mut DataContainer<K>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for loaded in self.loaded.by_ref() {
+            let Loaded {
+                hash,
+                location,
+                container,
+                fingerprint: loaded_fingerprint,
+            } = loaded;
+
+            let read = File::open(container_path(self.path, location.container))
+                .and_then(|mut file| read_record(&mut file, *location))
+                .and_then(|record| decode(&record).map(|decoded| (decoded, record)));
+            match read {
+                Ok((decoded, record)) => {
+                    *loaded_fingerprint = fingerprint(&record);
+                    return Some((hash, container.insert(decoded)));
+                }
+                Err(error) => {
+                    self.deferred_error.get_or_insert(error);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<Hash, K> Drop for DiskDatabase<Hash, K>
+where
+    Hash: ChunkHash + Encode,
+    K: Encode,
+{
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+/// Persistent target map, which keeps data of the scrubbed chunks on disk.
+///
+/// The data is stored the same way as the chunks of a [`DiskDatabase`], in a separate directory.
+/// A file system that was scrubbed can only be [reopened][crate::FileSystem::open_existing]
+/// with a target map that persists the data, e.g. this one.
+pub struct DiskTargetMap<K>
+where
+    K: ChunkHash + Encode,
+{
+    database: DiskDatabase<K, K>,
+}
+
+impl<K> DiskTargetMap<K>
+where
+    K: ChunkHash + Encode,
+{
+    /// Creates a new target map in the directory at the given `path`, creating the directory if necessary.
+    ///
+    /// Returns `ErrorKind::AlreadyExists`, if the directory already contains a target map or a database.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            database: DiskDatabase::create(path)?,
+        })
+    }
+
+    /// Opens an existing target map in the directory at the given `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            database: DiskDatabase::open(path)?,
+        })
+    }
+
+    /// Returns the amount of keys stored in the target map.
+    pub fn len(&self) -> usize {
+        self.database.len()
+    }
+
+    /// Returns `true` if the target map contains no data.
+    pub fn is_empty(&self) -> bool {
+        self.database.is_empty()
+    }
+}
+
+impl<K> Database<K, Vec<u8>> for DiskTargetMap<K>
+where
+    K: ChunkHash + Encode,
+{
+    fn insert(&mut self, key: K, value: Vec<u8>) -> io::Result<()> {
+        self.database.insert(key, DataContainer::from(value))
+    }
+
+    fn insert_multi_borrowed(&mut self, pairs: Vec<(K, &[u8])>) -> io::Result<()> {
+        self.database.append_chunks(pairs)
+    }
+
+    /// Returns `ErrorKind::InvalidData`, if the data stored for the key is not a plain chunk.
+    fn get(&self, key: &K) -> io::Result<Vec<u8>> {
+        match self.database.get(key)?.extract() {
+            Data::Chunk(data) => Ok(data.clone()),
+            _ => Err(ErrorKind::InvalidData.into()),
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.database.remove(key)
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.database.contains(key)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.database.sync()
+    }
+}
+
+fn container_path(path: &Path, id: u32) -> PathBuf {
+    path.join(format!("container-{id}"))
+}
+
+fn open_container(path: &Path, id: u32) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(container_path(path, id))
+}
+
+/// Reads the encoded container at the given location of the container file.
+fn read_record(file: &mut File, location: Location) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(location.offset))?;
+
+    let mut record = vec![0; location.length as usize];
+    file.read_exact(&mut record)?;
+    Ok(record)
+}
+
+fn fingerprint(record: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(record);
+    hasher.finish()
+}
+
+/// Writes an index entry, which records that the container of `key` is stored at `location`.
+fn write_index_entry<Hash: Encode>(buf: &mut Vec<u8>, key: &Hash, location: Location) {
+    buf.push(INSERT_RECORD);
+    write_bytes(buf, &key.to_bytes());
+    buf.extend_from_slice(&location.container.to_le_bytes());
+    buf.extend_from_slice(&location.offset.to_le_bytes());
+    buf.extend_from_slice(&location.length.to_le_bytes());
+}
+
+/// Writes length of the `bytes` followed by the bytes themselves.
+pub(crate) fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Reader over encoded bytes.
+pub(crate) struct Cursor<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// Returns `true` if all bytes were read.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+fn encode<K: Encode>(container: &DataContainer<K>) -> Vec<u8> {
+    match container.extract() {
+        Data::Chunk(chunk) => {
+            let mut record = Vec::with_capacity(chunk.len() + 1);
+            record.push(CHUNK_TAG);
+            record.extend_from_slice(chunk);
+            record
+        }
+        Data::TargetChunk(keys) => {
+            let mut record = vec![TARGET_CHUNK_TAG];
+            write_keys(&mut record, keys);
+            record
+        }
+        Data::DeltaChunk { base, delta } => {
+            let mut record = vec![DELTA_CHUNK_TAG];
+            write_keys(&mut record, base);
+            write_keys(&mut record, delta);
+            record
+        }
+    }
+}
+
+fn write_keys<K: Encode>(record: &mut Vec<u8>, keys: &[K]) {
+    record.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+    for key in keys {
+        write_bytes(record, &key.to_bytes());
+    }
+}
+
+fn read_keys<K: Encode>(cursor: &mut Cursor) -> io::Result<Vec<K>> {
+    let count = cursor.u32()?;
+    (0..count).map(|_| K::from_bytes(cursor.bytes()?)).collect()
+}
+
+fn decode<K: Encode>(record: &[u8]) -> io::Result<DataContainer<K>> {
+    let mut cursor = Cursor::new(record);
+    match cursor.u8()? {
+        CHUNK_TAG => Ok(DataContainer::from(cursor.bytes.to_vec())),
+        TARGET_CHUNK_TAG => {
+            let keys = read_keys(&mut cursor)?;
+
+            let mut container = DataContainer::from(vec![]);
+            container.make_target(keys);
+            Ok(container)
+        }
+        DELTA_CHUNK_TAG => {
+            let base = read_keys(&mut cursor)?;
+            let delta = read_keys(&mut cursor)?;
+
+            let mut container = DataContainer::from(vec![]);
+            container.make_delta(base, delta);
+            Ok(container)
+        }
+        _ => Err(ErrorKind::InvalidData.into()),
+    }
+}
+
+/// Replays the index file, stopping at the first incomplete record.
+fn read_index<Hash: ChunkHash + Encode>(bytes: &[u8]) -> io::Result<HashMap<Hash, Location>> {
+    let mut index = HashMap::new();
+    let mut cursor = Cursor::new(bytes);
+
+    while !cursor.is_empty() {
+        let (hash, location) = match read_index_record(&mut cursor) {
+            Ok(record) => record,
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        };
+
+        let hash = Hash::from_bytes(hash)?;
+        match location {
+            Some(location) => index.insert(hash, location),
+            None => index.remove(&hash),
+        };
+    }
+
+    Ok(index)
+}
+
+/// Reads a single index record. Location is `None` for removal records.
+fn read_index_record<'a>(cursor: &mut Cursor<'a>) -> io::Result<(&'a [u8], Option<Location>)> {
+    let kind = cursor.u8()?;
+    let hash = cursor.bytes()?;
+    let location = match kind {
+        INSERT_RECORD => Some(Location {
+            container: cursor.u32()?,
+            offset: cursor.u64()?,
+            length: cursor.u64()?,
+        }),
+        REMOVE_RECORD => None,
+        _ => return Err(ErrorKind::InvalidData.into()),
+    };
+    Ok((hash, location))
+}
+
+impl Encode for Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        Ok(bytes.to_vec())
+    }
+}
+
+macro_rules! impl_encode_for_int {
+    ($($int:ty),*) => {
+        $(
+            impl Encode for $int {
+                fn to_bytes(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+                    let bytes = bytes.try_into().map_err(|_| ErrorKind::InvalidData)?;
+                    Ok(<$int>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_encode_for_int!(i32, u32, i64, u64, usize);
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::disk::DiskDatabase;
+    use crate::map::Database;
+    use crate::storage::{Data, DataContainer};
+
+    fn temp_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("chunkfs-disk-{}-{id}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn containers_survive_reopening() {
+        let path = temp_dir();
+        {
+            let mut db: DiskDatabase<Vec<u8>, u64> =
+                DiskDatabase::create(&path).unwrap().with_container_size(16);
+            for i in 0..10u8 {
+                db.insert(vec![i], DataContainer::from(vec![i; 10]))
+                    .unwrap();
+            }
+            db.remove(&vec![3]);
+            db.flush().unwrap();
+        }
+
+        let db: DiskDatabase<Vec<u8>, u64> = DiskDatabase::open(&path).unwrap();
+        assert_eq!(db.len(), 9);
+        assert!(!db.contains(&vec![3]));
+        for i in (0..10u8).filter(|i| *i != 3) {
+            match db.get(&vec![i]).unwrap().extract() {
+                Data::Chunk(chunk) => assert_eq!(chunk, &vec![i; 10]),
+                _ => panic!("expected a chunk"),
+            }
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn borrowed_chunks_are_inserted_across_containers() {
+        let path = temp_dir();
+        let buffer = (0..100u8).collect::<Vec<_>>();
+        {
+            let mut db: DiskDatabase<Vec<u8>, u64> =
+                DiskDatabase::create(&path).unwrap().with_container_size(25);
+            let pairs = buffer
+                .chunks(10)
+                .enumerate()
+                .map(|(i, chunk)| (vec![i as u8], chunk))
+                .collect();
+            db.insert_multi_borrowed(pairs).unwrap();
+            assert_eq!(db.len(), 10);
+            db.flush().unwrap();
+        }
+
+        let db: DiskDatabase<Vec<u8>, u64> = DiskDatabase::open(&path).unwrap();
+        for (i, chunk) in buffer.chunks(10).enumerate() {
+            match db.get(&vec![i as u8]).unwrap().extract() {
+                Data::Chunk(stored) => assert_eq!(stored, chunk),
+                _ => panic!("expected a chunk"),
+            }
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn changes_made_through_iterator_are_written_back() {
+        let path = temp_dir();
+        {
+            let mut db: DiskDatabase<Vec<u8>, u64> = DiskDatabase::create(&path).unwrap();
+            db.insert(vec![1], DataContainer::from(vec![1; 10]))
+                .unwrap();
+            db.insert(vec![2], DataContainer::from(vec![2; 10]))
+                .unwrap();
+            db.insert(vec![3], DataContainer::from(vec![3; 10]))
+                .unwrap();
+
+            for (hash, container) in &mut db {
+                if hash == &vec![1] {
+                    container.make_target(vec![10, 20]);
+                }
+                if hash == &vec![3] {
+                    container.make_delta(vec![10], vec![30, 40]);
+                }
+            }
+            db.flush().unwrap();
+        }
+
+        let db: DiskDatabase<Vec<u8>, u64> = DiskDatabase::open(&path).unwrap();
+        match db.get(&vec![1]).unwrap().extract() {
+            Data::TargetChunk(keys) => assert_eq!(keys, &vec![10, 20]),
+            _ => panic!("expected target keys"),
+        }
+        match db.get(&vec![3]).unwrap().extract() {
+            Data::DeltaChunk { base, delta } => {
+                assert_eq!(base, &vec![10]);
+                assert_eq!(delta, &vec![30, 40]);
+            }
+            _ => panic!("expected base and delta keys"),
+        }
+        assert!(matches!(
+            db.get(&vec![2]).unwrap().extract(),
+            Data::Chunk(_)
+        ));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn changes_made_by_for_each_mut_are_written_back() {
+        let path = temp_dir();
+        {
+            let mut db: DiskDatabase<Vec<u8>, u64> =
+                DiskDatabase::create(&path).unwrap().with_container_size(25);
+            for i in 0..10u8 {
+                db.insert(vec![i], DataContainer::from(vec![i; 10]))
+                    .unwrap();
+            }
+
+            let mut visited = 0;
+            db.for_each_mut(|hash, container| {
+                visited += 1;
+                if hash[0] % 2 == 0 {
+                    container.make_target(vec![hash[0] as u64]);
+                }
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(visited, 10);
+            assert!(db.loaded.is_empty());
+        }
+
+        let db: DiskDatabase<Vec<u8>, u64> = DiskDatabase::open(&path).unwrap();
+        for i in 0..10u8 {
+            match (i % 2, db.get(&vec![i]).unwrap().extract()) {
+                (0, Data::TargetChunk(keys)) => assert_eq!(keys, &vec![i as u64]),
+                (1, Data::Chunk(chunk)) => assert_eq!(chunk, &vec![i; 10]),
+                _ => panic!("unexpected container"),
+            }
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn iterator_reads_containers_lazily() {
+        let path = temp_dir();
+        let mut db: DiskDatabase<Vec<u8>, u64> = DiskDatabase::create(&path).unwrap();
+        for i in 0..10u8 {
+            db.insert(vec![i], DataContainer::from(vec![i; 10]))
+                .unwrap();
+        }
+
+        let (hash, container) = (&mut db).into_iter().next().unwrap();
+        let hash = hash.clone();
+        container.make_target(vec![1]);
+        let read = db
+            .loaded
+            .iter()
+            .filter(|loaded| loaded.container.is_some())
+            .count();
+        assert_eq!(read, 1);
+        assert!(matches!(
+            db.get(&hash).unwrap().extract(),
+            Data::TargetChunk(_)
+        ));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn containers_reached_by_iterator_are_released_by_next_change() {
+        let path = temp_dir();
+        let mut db: DiskDatabase<Vec<u8>, u64> = DiskDatabase::create(&path).unwrap();
+        for i in 0..10u8 {
+            db.insert(vec![i], DataContainer::from(vec![i; 10]))
+                .unwrap();
+        }
+
+        for (hash, container) in &mut db {
+            container.make_target(vec![hash[0] as u64]);
+        }
+        assert_eq!(db.loaded.len(), 10);
+
+        db.insert(vec![10], DataContainer::from(vec![10; 10]))
+            .unwrap();
+        assert!(db.loaded.is_empty());
+        assert!(db.positions.is_empty());
+        for i in 0..10u8 {
+            match db.get(&vec![i]).unwrap().extract() {
+                Data::TargetChunk(keys) => assert_eq!(keys, &vec![i as u64]),
+                _ => panic!("unexpected container"),
+            }
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn cant_create_database_twice() {
+        let path = temp_dir();
+        let _db: DiskDatabase<Vec<u8>, u64> = DiskDatabase::create(&path).unwrap();
+
+        let result = DiskDatabase::<Vec<u8>, u64>::create(&path);
+        assert_eq!(
+            result.err().unwrap().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,11 +1,16 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::cmp::min;
+use std::collections::{BTreeSet, HashMap};
 use std::io;
 use std::io::ErrorKind;
+use std::ops::Range;
 
-use crate::storage::SpansInfo;
+use crate::disk::{write_bytes, Cursor};
+use crate::storage::{Span, SpansInfo};
 use crate::ChunkHash;
 use crate::Chunker;
-use crate::{WriteMeasurements, SEG_SIZE};
+use crate::Encode;
+use crate::{ReadMeasurements, WriteMeasurements};
 
 /// Hashed span, starting at `offset`.
 #[derive(Debug, PartialEq, Eq, Default)]
@@ -19,12 +24,39 @@ pub struct FileSpan<Hash: ChunkHash> {
 pub struct File<Hash: ChunkHash> {
     name: String,
     spans: Vec<FileSpan<Hash>>,
+    size: usize,
 }
 
-/// Layer that contains all [`files`][File], accessed by their names.
-#[derive(Default)]
+/// A directory, containing names of the files and directories that are located in it.
+#[derive(Debug, Default)]
+pub struct Directory {
+    entries: BTreeSet<String>,
+}
+
+/// Layer that contains all [`files`][File] and [`directories`][Directory], accessed by their paths.
+///
+/// Paths consist of names separated by `/`. Leading, trailing and repeated separators are ignored,
+/// so `"a/b"` and `"/a//b/"` refer to the same entry. The root directory always exists and has an empty path.
 pub struct FileLayer<Hash: ChunkHash> {
     files: HashMap<String, File<Hash>>,
+    directories: HashMap<String, Directory>,
+}
+
+/// Marks the beginning of the serialized [`FileLayer`].
+const METADATA_MAGIC: &[u8; 4] = b"CFSM";
+
+/// Version of the [`FileLayer`] serialization format.
+const METADATA_VERSION: u32 = 1;
+
+/// Mode in which a [`file`][File] is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// The handle starts at the beginning of the file and can only be used for reading.
+    Read,
+    /// The handle starts at the end of the file, written data is appended to it.
+    Append,
+    /// The file is truncated to zero length, written data replaces its contents.
+    Truncate,
 }
 
 /// Handle for an open [`file`][File].
@@ -38,9 +70,20 @@ where
     // or it would count as an immutable reference for FileSystem
     file_name: String,
     offset: usize,
+    mode: OpenMode,
     measurements: WriteMeasurements,
     // maybe not pub(crate) but something else? cannot think of anything
     pub(crate) chunker: C,
+    /// Whether the chunker's remainder contains data written to the end of the file that is not stored yet.
+    pub(crate) remainder_pending: bool,
+    /// Index of the first span changed through the handle that is not recorded in the journal yet.
+    pub(crate) uncommitted: Option<usize>,
+    /// Identifies the handle among the handles opened by the file system.
+    pub(crate) id: u64,
+    /// Whether the last span of the file has to be moved back to the chunker before data is appended through the handle.
+    pub(crate) restore_pending: bool,
+    // a cell, because reads only borrow the handle
+    read_measurements: Cell<ReadMeasurements>,
 }
 
 impl<Hash: ChunkHash> File<Hash> {
@@ -48,6 +91,7 @@ impl<Hash: ChunkHash> File<Hash> {
         File {
             name,
             spans: vec![],
+            size: 0,
         }
     }
 }
@@ -56,12 +100,18 @@ impl<C> FileHandle<C>
 where
     C: Chunker,
 {
-    fn new<Hash: ChunkHash>(file: &File<Hash>, chunker: C) -> Self {
+    fn new<Hash: ChunkHash>(file: &File<Hash>, chunker: C, mode: OpenMode) -> Self {
         FileHandle {
             file_name: file.name.clone(),
             offset: 0,
+            mode,
             measurements: Default::default(),
             chunker,
+            remainder_pending: false,
+            uncommitted: None,
+            id: 0,
+            restore_pending: false,
+            read_measurements: Cell::default(),
         }
     }
 
@@ -70,98 +120,623 @@ where
         &self.file_name
     }
 
+    /// Points the handle to the file's new name after it was renamed.
+    #[cfg(feature = "fuse")]
+    pub(crate) fn set_name(&mut self, name: String) {
+        self.file_name = name;
+    }
+
+    /// Returns the mode in which the file was opened.
+    pub fn mode(&self) -> OpenMode {
+        self.mode
+    }
+
+    /// Returns current offset of the handle in the file.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Moves the handle to the given `offset`, so that the next [`read`][crate::FileSystem::read_from_file]
+    /// starts from it. Offsets past the end of the file are allowed, reading from them returns no data.
+    ///
+    /// Seeking only affects reads: data is always written to the end of the file,
+    /// and the handle is moved to the new end after writing.
+    pub fn seek(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    /// Returns [`ReadMeasurements`] of all reads made through the handle.
+    pub fn read_measurements(&self) -> ReadMeasurements {
+        self.read_measurements.get()
+    }
+
+    /// Adds measurements of a read made through the handle.
+    pub(crate) fn add_read_measurements(&self, measurements: ReadMeasurements) {
+        self.read_measurements
+            .set(self.read_measurements.get() + measurements);
+    }
+
     /// Closes handle and returns [`WriteMeasurements`] made while file was open.
     pub(crate) fn close(self) -> WriteMeasurements {
         self.measurements
     }
 }
 
+impl<Hash: ChunkHash> Default for FileLayer<Hash> {
+    fn default() -> Self {
+        Self {
+            files: HashMap::new(),
+            directories: HashMap::from([(String::new(), Directory::default())]),
+        }
+    }
+}
+
+/// Brings `path` to the form in which it is stored in the [`FileLayer`]:
+/// names separated by a single `/`, without leading or trailing separators.
+pub(crate) fn normalize(path: &str) -> String {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Splits normalized `path` into the path of its parent directory and the name of the entry.
+fn split_parent(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Replaces spans of the `file` in the given `range` with the new ones,
+/// shifting offsets of the spans that follow. Returns hashes of the replaced spans.
+fn splice_spans<Hash: ChunkHash>(
+    file: &mut File<Hash>,
+    range: Range<usize>,
+    spans: Vec<Span<Hash>>,
+) -> Vec<Hash> {
+    let start = file
+        .spans
+        .get(range.start)
+        .map_or(file.size, |span| span.offset);
+    let old_end = file
+        .spans
+        .get(range.end)
+        .map_or(file.size, |span| span.offset);
+
+    let mut offset = start;
+    let spans = spans
+        .into_iter()
+        .map(|span| {
+            let file_span = FileSpan {
+                hash: span.hash,
+                offset,
+            };
+            offset += span.length;
+            file_span
+        })
+        .collect::<Vec<_>>();
+    let new_end = offset;
+    let inserted = spans.len();
+
+    let replaced = file
+        .spans
+        .splice(range.clone(), spans)
+        .map(|span| span.hash)
+        .collect();
+    for span in &mut file.spans[range.start + inserted..] {
+        span.offset = span.offset - old_end + new_end;
+    }
+    file.size = file.size - old_end + new_end;
+    replaced
+}
+
 impl<Hash: ChunkHash> FileLayer<Hash> {
-    /// Creates a [`file`][File] and returns its [`FileHandle`]
+    /// Creates a [`file`][File] and returns its [`FileHandle`].
+    ///
+    /// Returns `ErrorKind::NotFound`, if the parent directory doesn't exist,
+    /// and `ErrorKind::IsADirectory`, if there is a directory with the same path.
     pub fn create<C: Chunker>(
         &mut self,
         name: String,
         chunker: C,
         create_new: bool,
     ) -> io::Result<FileHandle<C>> {
-        if !create_new && self.files.contains_key(&name) {
+        let path = self.insert(&name, create_new)?;
+        let written_file = self.files.get(&path).unwrap();
+        Ok(FileHandle::new(written_file, chunker, OpenMode::Truncate))
+    }
+
+    /// Creates an empty [`file`][File] without opening it and returns its normalized path.
+    /// Errors are the same as for [`create`][FileLayer::create].
+    pub fn insert(&mut self, name: &str, create_new: bool) -> io::Result<String> {
+        let path = normalize(name);
+        if path.is_empty() || self.directories.contains_key(&path) {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        if !create_new && self.files.contains_key(&path) {
             return Err(ErrorKind::AlreadyExists.into());
         }
 
-        let file = File::new(name.clone());
-        let _ = self.files.insert(name.clone(), file);
-        let written_file = self.files.get(&name).unwrap();
-        Ok(FileHandle::new(written_file, chunker))
+        let (parent, file_name) = split_parent(&path);
+        let file_name = file_name.to_string();
+        self.parent_dir_mut(parent)?.entries.insert(file_name);
+
+        let file = File::new(path.clone());
+        let _ = self.files.insert(path.clone(), file);
+        Ok(path)
     }
 
-    /// Opens a [`file`][File] based on its name and returns its [`FileHandle`]
-    pub fn open<C: Chunker>(&self, name: &str, chunker: C) -> io::Result<FileHandle<C>> {
+    /// Opens a [`file`][File] based on its path and returns its [`FileHandle`]
+    pub fn open<C: Chunker>(
+        &self,
+        name: &str,
+        chunker: C,
+        mode: OpenMode,
+    ) -> io::Result<FileHandle<C>> {
+        let path = normalize(name);
+        if self.directories.contains_key(&path) {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+
         self.files
-            .get(name)
-            .map(|file| FileHandle::new(file, chunker))
+            .get(&path)
+            .map(|file| FileHandle::new(file, chunker, mode))
             .ok_or(ErrorKind::NotFound.into())
     }
 
+    /// Removes a [`file`][File] based on its path and returns hashes of all its spans.
+    pub fn delete(&mut self, name: &str) -> io::Result<Vec<Hash>> {
+        let path = normalize(name);
+        let file = self.files.remove(&path).ok_or(ErrorKind::NotFound)?;
+
+        let (parent, file_name) = split_parent(&path);
+        if let Some(directory) = self.directories.get_mut(parent) {
+            directory.entries.remove(file_name);
+        }
+
+        Ok(file.spans.into_iter().map(|span| span.hash).collect())
+    }
+
+    /// Renames a [`file`][File], keeping its contents. The file can be moved to another existing directory.
+    ///
+    /// Returns `ErrorKind::NotFound`, if there is no file at `old` or the new parent directory doesn't exist,
+    /// and `ErrorKind::AlreadyExists`, if a file or directory at `new` already exists.
+    pub fn rename(&mut self, old: &str, new: String) -> io::Result<()> {
+        let old = normalize(old);
+        let new = normalize(&new);
+        if !self.files.contains_key(&old) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if old == new {
+            return Ok(());
+        }
+        if self.files.contains_key(&new) || self.directories.contains_key(&new) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+
+        let (new_parent, new_name) = split_parent(&new);
+        let new_name = new_name.to_string();
+        self.parent_dir_mut(new_parent)?.entries.insert(new_name);
+
+        let (old_parent, old_name) = split_parent(&old);
+        if let Some(directory) = self.directories.get_mut(old_parent) {
+            directory.entries.remove(old_name);
+        }
+
+        let mut file = self.files.remove(&old).unwrap();
+        file.name = new.clone();
+        self.files.insert(new, file);
+        Ok(())
+    }
+
+    /// Creates an empty [`directory`][Directory] at the given path.
+    ///
+    /// Returns `ErrorKind::NotFound`, if the parent directory doesn't exist,
+    /// and `ErrorKind::AlreadyExists`, if a file or directory with the same path exists.
+    pub fn mkdir(&mut self, path: &str) -> io::Result<()> {
+        let path = normalize(path);
+        if self.files.contains_key(&path) || self.directories.contains_key(&path) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+
+        let (parent, dir_name) = split_parent(&path);
+        let dir_name = dir_name.to_string();
+        self.parent_dir_mut(parent)?.entries.insert(dir_name);
+
+        self.directories.insert(path, Directory::default());
+        Ok(())
+    }
+
+    /// Returns names of all entries of the [`directory`][Directory] in lexicographical order.
+    ///
+    /// Returns `ErrorKind::NotFound`, if the directory doesn't exist,
+    /// and `ErrorKind::NotADirectory`, if the path belongs to a file.
+    pub fn list_dir(&self, path: &str) -> io::Result<Vec<String>> {
+        let path = normalize(path);
+        if self.files.contains_key(&path) {
+            return Err(ErrorKind::NotADirectory.into());
+        }
+
+        let directory = self.directories.get(&path).ok_or(ErrorKind::NotFound)?;
+        Ok(directory.entries.iter().cloned().collect())
+    }
+
+    /// Removes an empty [`directory`][Directory].
+    ///
+    /// Returns `ErrorKind::NotFound`, if the directory doesn't exist,
+    /// `ErrorKind::NotADirectory`, if the path belongs to a file,
+    /// `ErrorKind::DirectoryNotEmpty`, if the directory has any entries,
+    /// and `ErrorKind::InvalidInput` on attempt to remove the root directory.
+    pub fn remove_dir(&mut self, path: &str) -> io::Result<()> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        if self.files.contains_key(&path) {
+            return Err(ErrorKind::NotADirectory.into());
+        }
+
+        let directory = self.directories.get(&path).ok_or(ErrorKind::NotFound)?;
+        if !directory.entries.is_empty() {
+            return Err(ErrorKind::DirectoryNotEmpty.into());
+        }
+
+        self.directories.remove(&path);
+        let (parent, dir_name) = split_parent(&path);
+        if let Some(directory) = self.directories.get_mut(parent) {
+            directory.entries.remove(dir_name);
+        }
+        Ok(())
+    }
+
+    /// Checks if the directory with the given path exists.
+    pub fn dir_exists(&self, path: &str) -> bool {
+        self.directories.contains_key(&normalize(path))
+    }
+
+    /// Returns mutable reference to the directory which is going to contain a new entry.
+    fn parent_dir_mut(&mut self, parent: &str) -> io::Result<&mut Directory> {
+        if self.files.contains_key(parent) {
+            return Err(ErrorKind::NotADirectory.into());
+        }
+        Ok(self
+            .directories
+            .get_mut(parent)
+            .ok_or(ErrorKind::NotFound)?)
+    }
+
     /// Returns reference to a file using [`FileHandle`] that corresponds to it.
-    fn find_file<C: Chunker>(&self, handle: &FileHandle<C>) -> &File<Hash> {
-        self.files.get(&handle.file_name).unwrap()
+    ///
+    /// Returns `ErrorKind::NotFound`, if the file was deleted or renamed after the handle was opened.
+    fn find_file<C: Chunker>(&self, handle: &FileHandle<C>) -> io::Result<&File<Hash>> {
+        Ok(self
+            .files
+            .get(&handle.file_name)
+            .ok_or(ErrorKind::NotFound)?)
     }
 
     /// Returns mutable reference to a file using [`FileHandle`] that corresponds to it.
-    fn find_file_mut<C: Chunker>(&mut self, handle: &FileHandle<C>) -> &mut File<Hash> {
-        self.files.get_mut(&handle.file_name).unwrap()
+    ///
+    /// Returns `ErrorKind::NotFound`, if the file was deleted or renamed after the handle was opened.
+    fn find_file_mut<C: Chunker>(&mut self, handle: &FileHandle<C>) -> io::Result<&mut File<Hash>> {
+        Ok(self
+            .files
+            .get_mut(&handle.file_name)
+            .ok_or(ErrorKind::NotFound)?)
     }
 
     /// Reads all hashes of the file, from beginning to end.
-    pub fn read_complete<C: Chunker>(&self, handle: &FileHandle<C>) -> Vec<Hash> {
-        let file = self.find_file(handle);
-        file.spans
+    pub fn read_complete<C: Chunker>(&self, handle: &FileHandle<C>) -> io::Result<Vec<Hash>> {
+        let file = self.find_file(handle)?;
+        Ok(file
+            .spans
             .iter()
             .map(|span| span.hash.clone()) // cloning hashes, takes a lot of time
-            .collect()
+            .collect())
     }
 
     /// Writes spans to the end of the file.
-    pub fn write<C: Chunker>(&mut self, handle: &mut FileHandle<C>, info: SpansInfo<Hash>) {
-        let file = self.find_file_mut(handle);
+    pub fn write<C: Chunker>(
+        &mut self,
+        handle: &mut FileHandle<C>,
+        info: SpansInfo<Hash>,
+    ) -> io::Result<()> {
+        let file = self.find_file_mut(handle)?;
         for span in info.spans {
             file.spans.push(FileSpan {
                 hash: span.hash,
-                offset: handle.offset,
+                offset: file.size,
             });
-            handle.offset += span.length;
+            file.size += span.length;
         }
+        handle.offset = file.size;
 
         handle.measurements += info.measurements;
+        Ok(())
+    }
+
+    /// Returns index of the span containing byte at `offset`.
+    /// If `offset` is past the end of the file, returns index of the last span, or `0` if the file is empty.
+    pub fn span_index<C: Chunker>(
+        &self,
+        handle: &FileHandle<C>,
+        offset: usize,
+    ) -> io::Result<usize> {
+        let file = self.find_file(handle)?;
+        Ok(file
+            .spans
+            .partition_point(|span| span.offset <= offset)
+            .saturating_sub(1))
     }
 
-    /// Reads 1 MB of data from the open file and returns received hashes,
-    /// starting point is based on the `FileHandle`'s offset.
-    pub fn read<C: Chunker>(&self, handle: &mut FileHandle<C>) -> Vec<Hash> {
-        let file = self.find_file(handle);
+    /// Returns offset of the span with the given `index`, or the size of the file if there is no such span.
+    pub fn span_offset<C: Chunker>(
+        &self,
+        handle: &FileHandle<C>,
+        index: usize,
+    ) -> io::Result<usize> {
+        let file = self.find_file(handle)?;
+        Ok(file.spans.get(index).map_or(file.size, |span| span.offset))
+    }
+
+    /// Returns amount of spans in the file.
+    pub fn span_count<C: Chunker>(&self, handle: &FileHandle<C>) -> io::Result<usize> {
+        Ok(self.find_file(handle)?.spans.len())
+    }
+
+    /// Replaces spans of the file in the given `range` with the new ones,
+    /// shifting offsets of the spans that follow. Returns hashes of the replaced spans.
+    pub fn splice<C: Chunker>(
+        &mut self,
+        handle: &mut FileHandle<C>,
+        range: Range<usize>,
+        info: SpansInfo<Hash>,
+    ) -> io::Result<Vec<Hash>> {
+        let file = self.find_file_mut(handle)?;
+        let replaced = splice_spans(file, range, info.spans);
+        handle.measurements += info.measurements;
+        Ok(replaced)
+    }
+
+    /// Replaces spans of the file with the given `name` in the given `range` with the new ones,
+    /// shifting offsets of the spans that follow. The end of the range is clamped to the amount of spans.
+    /// Returns hashes of the replaced spans.
+    pub fn splice_by_name(
+        &mut self,
+        name: &str,
+        range: Range<usize>,
+        spans: Vec<Span<Hash>>,
+    ) -> io::Result<Vec<Hash>> {
+        let file = self
+            .files
+            .get_mut(&normalize(name))
+            .ok_or(ErrorKind::NotFound)?;
+        let range = range.start..min(range.end, file.spans.len());
+        if range.start > range.end {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        Ok(splice_spans(file, range, spans))
+    }
 
-        let mut bytes_read = 0;
-        let mut last_offset = handle.offset;
-        let hashes = file
+    /// Returns spans of the file with the given `name`, starting from the one with the given `index`.
+    pub fn spans_from(&self, name: &str, index: usize) -> io::Result<Vec<Span<Hash>>> {
+        let file = self
+            .files
+            .get(&normalize(name))
+            .ok_or(ErrorKind::NotFound)?;
+        Ok((index..file.spans.len())
+            .map(|i| {
+                let end = file.spans.get(i + 1).map_or(file.size, |span| span.offset);
+                Span::new(file.spans[i].hash.clone(), end - file.spans[i].offset)
+            })
+            .collect())
+    }
+
+    /// Truncates the file to `len` bytes and returns hashes of the removed spans.
+    ///
+    /// If `len` falls inside a span, the span is replaced by the one returned by `cut`,
+    /// which receives hash of the original span and the amount of its bytes that are kept.
+    /// Returns `ErrorKind::InvalidInput`, if `len` is larger than the file size.
+    pub fn truncate(
+        &mut self,
+        name: &str,
+        len: usize,
+        cut: impl FnOnce(&Hash, usize) -> io::Result<Span<Hash>>,
+    ) -> io::Result<Vec<Hash>> {
+        let file = self
+            .files
+            .get_mut(&normalize(name))
+            .ok_or(ErrorKind::NotFound)?;
+        if len > file.size {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        // spans starting before `len` are kept, the last of them may need to be cut
+        let kept = file.spans.partition_point(|span| span.offset < len);
+        let kept_end = file.spans.get(kept).map_or(file.size, |span| span.offset);
+        let cut_span = match kept_end > len {
+            true => {
+                let last = &file.spans[kept - 1];
+                Some(cut(&last.hash, len - last.offset)?)
+            }
+            false => None,
+        };
+
+        let mut removed = file
             .spans
+            .drain(kept..)
+            .map(|span| span.hash)
+            .collect::<Vec<_>>();
+        if let Some(span) = cut_span {
+            let last = file.spans.last_mut().unwrap();
+            removed.push(std::mem::replace(&mut last.hash, span.hash));
+        }
+        file.size = len;
+
+        Ok(removed)
+    }
+
+    /// Finds spans that overlap with bytes `[offset, offset + len)` of the open file
+    /// and returns their hashes, along with the offset of the first found span in the file.
+    ///
+    /// The first span is found using binary search over span offsets.
+    pub fn read_at<C: Chunker>(
+        &self,
+        handle: &FileHandle<C>,
+        offset: usize,
+        len: usize,
+    ) -> io::Result<(usize, Vec<Hash>)> {
+        let file = self.find_file(handle)?;
+        let end = min(offset.saturating_add(len), file.size);
+        if offset >= end {
+            return Ok((offset, vec![]));
+        }
+
+        // the first span always starts at 0, so at least one span satisfies the predicate
+        let first = file.spans.partition_point(|span| span.offset <= offset) - 1;
+        let hashes = file.spans[first..]
             .iter()
-            .skip_while(|span| span.offset < handle.offset) // find current span in the file
-            .take_while(|span| {
-                bytes_read += span.offset - last_offset;
-                last_offset = span.offset;
-                bytes_read < SEG_SIZE
-            }) // take 1 MB of spans after current one
-            .map(|span| span.hash.clone()) // take their hashes
+            .take_while(|span| span.offset < end)
+            .map(|span| span.hash.clone())
             .collect();
 
-        handle.offset += bytes_read;
+        Ok((file.spans[first].offset, hashes))
+    }
 
-        hashes
+    /// Returns size of the file in bytes, counting only the data that was already stored.
+    pub fn file_size(&self, name: &str) -> io::Result<usize> {
+        self.files
+            .get(&normalize(name))
+            .map(|file| file.size)
+            .ok_or(ErrorKind::NotFound.into())
     }
 
-    /// Checks if the file with the given name exists.
+    /// Checks if the file with the given path exists.
     pub fn file_exists(&self, name: &str) -> bool {
-        self.files.contains_key(name)
+        self.files.contains_key(&normalize(name))
+    }
+
+    /// Returns total size of all files.
+    pub fn total_size(&self) -> usize {
+        self.files.values().map(|file| file.size).sum()
+    }
+
+    /// Returns hashes and lengths of all spans of all files, including repeated ones.
+    pub fn span_lengths(&self) -> impl Iterator<Item = (&Hash, usize)> {
+        self.files.values().flat_map(|file| {
+            file.spans.iter().enumerate().map(|(i, span)| {
+                let end = file.spans.get(i + 1).map_or(file.size, |next| next.offset);
+                (&span.hash, end - span.offset)
+            })
+        })
+    }
+
+    /// Adds `name` to the entries of the parent directory, which must already exist.
+    fn link(&mut self, path: &str) -> io::Result<()> {
+        let (parent, name) = split_parent(path);
+        let directory = self
+            .directories
+            .get_mut(parent)
+            .ok_or(ErrorKind::InvalidData)?;
+        directory.entries.insert(name.to_string());
+        Ok(())
+    }
+}
+
+/// Serialized form of the [`FileLayer`]: magic bytes and format version,
+/// followed by paths of the directories and the files with their sizes and spans.
+/// All numbers are little-endian.
+impl<Hash: ChunkHash + Encode> Encode for FileLayer<Hash> {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = METADATA_MAGIC.to_vec();
+        bytes.extend_from_slice(&METADATA_VERSION.to_le_bytes());
+
+        let mut directories: Vec<_> = self
+            .directories
+            .keys()
+            .filter(|path| !path.is_empty())
+            .collect();
+        // parents go before their subdirectories
+        directories.sort();
+        bytes.extend_from_slice(&(directories.len() as u32).to_le_bytes());
+        for path in directories {
+            write_bytes(&mut bytes, path.as_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.files.len() as u32).to_le_bytes());
+        for file in self.files.values() {
+            write_bytes(&mut bytes, file.name.as_bytes());
+            bytes.extend_from_slice(&(file.size as u64).to_le_bytes());
+            bytes.extend_from_slice(&(file.spans.len() as u32).to_le_bytes());
+            for span in &file.spans {
+                write_bytes(&mut bytes, &span.hash.to_bytes());
+                bytes.extend_from_slice(&(span.offset as u64).to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    /// Restores the file layer from bytes.
+    ///
+    /// Returns `ErrorKind::Unsupported`, if the bytes were written by a different version of the format,
+    /// and `ErrorKind::InvalidData`, if they are not a consistent file layer.
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut cursor = Cursor::new(bytes);
+        let invalid = |_| io::Error::from(ErrorKind::InvalidData);
+
+        let magic = [cursor.u8()?, cursor.u8()?, cursor.u8()?, cursor.u8()?];
+        if &magic != METADATA_MAGIC {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        if cursor.u32()? != METADATA_VERSION {
+            return Err(ErrorKind::Unsupported.into());
+        }
+
+        let mut layer = Self::default();
+        for _ in 0..cursor.u32()? {
+            let path = String::from_utf8(cursor.bytes()?.to_vec()).map_err(invalid)?;
+            if path != normalize(&path) || layer.directories.contains_key(&path) {
+                return Err(ErrorKind::InvalidData.into());
+            }
+            layer.link(&path)?;
+            layer.directories.insert(path, Directory::default());
+        }
+
+        for _ in 0..cursor.u32()? {
+            let path = String::from_utf8(cursor.bytes()?.to_vec()).map_err(invalid)?;
+            if path.is_empty()
+                || path != normalize(&path)
+                || layer.directories.contains_key(&path)
+                || layer.files.contains_key(&path)
+            {
+                return Err(ErrorKind::InvalidData.into());
+            }
+
+            let mut file = File::new(path.clone());
+            file.size = cursor.u64()? as usize;
+            for _ in 0..cursor.u32()? {
+                let hash = Hash::from_bytes(cursor.bytes()?)?;
+                let offset = cursor.u64()? as usize;
+                let after_previous = file
+                    .spans
+                    .last()
+                    .map_or(offset == 0, |last| last.offset < offset);
+                if !after_previous || offset >= file.size {
+                    return Err(ErrorKind::InvalidData.into());
+                }
+                file.spans.push(FileSpan { hash, offset });
+            }
+            if file.spans.is_empty() != (file.size == 0) {
+                return Err(ErrorKind::InvalidData.into());
+            }
+
+            layer.link(&path)?;
+            layer.files.insert(path, file);
+        }
+
+        if !cursor.is_empty() {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        Ok(layer)
     }
 }
 
@@ -171,6 +746,7 @@ mod tests {
 
     use crate::chunkers::FSChunker;
     use crate::file_layer::FileLayer;
+    use crate::Encode;
 
     #[test]
     fn file_layer_create_file() {
@@ -192,4 +768,127 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().kind(), ErrorKind::AlreadyExists);
     }
+
+    #[test]
+    fn delete_removes_file() {
+        let mut fl: FileLayer<Vec<u8>> = FileLayer::default();
+        fl.create("hello".to_string(), FSChunker::new(4096), false)
+            .unwrap();
+
+        assert_eq!(fl.delete("hello").unwrap(), Vec::<Vec<u8>>::new());
+        assert!(!fl.file_exists("hello"));
+        assert_eq!(fl.delete("hello").unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn rename_moves_file_to_new_name() {
+        let mut fl: FileLayer<Vec<u8>> = FileLayer::default();
+        fl.create("hello".to_string(), FSChunker::new(4096), false)
+            .unwrap();
+        fl.create("world".to_string(), FSChunker::new(4096), false)
+            .unwrap();
+
+        let result = fl.rename("hello", "world".to_string());
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        let result = fl.rename("missing", "other".to_string());
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+
+        fl.rename("hello", "bye".to_string()).unwrap();
+        assert!(!fl.file_exists("hello"));
+        assert_eq!(fl.files.get("bye").unwrap().name, "bye");
+    }
+
+    #[test]
+    fn files_are_created_inside_directories() {
+        let mut fl: FileLayer<Vec<u8>> = FileLayer::default();
+        fl.mkdir("dir").unwrap();
+        fl.mkdir("/dir/inner/").unwrap();
+        fl.create("dir/inner/file".to_string(), FSChunker::new(4096), false)
+            .unwrap();
+        fl.create("dir/file".to_string(), FSChunker::new(4096), false)
+            .unwrap();
+
+        assert!(fl.file_exists("/dir//inner/file"));
+        assert_eq!(fl.list_dir("").unwrap(), vec!["dir"]);
+        assert_eq!(fl.list_dir("dir").unwrap(), vec!["file", "inner"]);
+        assert_eq!(fl.list_dir("dir/inner").unwrap(), vec!["file"]);
+        assert_eq!(
+            fl.list_dir("dir/file").unwrap_err().kind(),
+            ErrorKind::NotADirectory
+        );
+    }
+
+    #[test]
+    fn cant_create_entries_in_missing_directory() {
+        let mut fl: FileLayer<Vec<u8>> = FileLayer::default();
+
+        let result = fl.create("missing/file".to_string(), FSChunker::new(4096), false);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(
+            fl.mkdir("missing/dir").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        fl.mkdir("dir").unwrap();
+        assert_eq!(
+            fl.mkdir("dir").unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+    }
+
+    #[test]
+    fn only_empty_directory_can_be_removed() {
+        let mut fl: FileLayer<Vec<u8>> = FileLayer::default();
+        fl.mkdir("dir").unwrap();
+        fl.create("dir/file".to_string(), FSChunker::new(4096), false)
+            .unwrap();
+
+        assert_eq!(
+            fl.remove_dir("dir").unwrap_err().kind(),
+            ErrorKind::DirectoryNotEmpty
+        );
+        assert_eq!(
+            fl.remove_dir("/").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        fl.delete("dir/file").unwrap();
+        fl.remove_dir("dir").unwrap();
+        assert!(!fl.dir_exists("dir"));
+        assert_eq!(fl.list_dir("").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            fl.remove_dir("dir").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let mut fl: FileLayer<Vec<u8>> = FileLayer::default();
+        fl.mkdir("a").unwrap();
+        fl.mkdir("a/b").unwrap();
+        fl.create("a/b/file".to_string(), FSChunker::new(4096), false)
+            .unwrap();
+        fl.create("file".to_string(), FSChunker::new(4096), false)
+            .unwrap();
+
+        let restored = FileLayer::<Vec<u8>>::from_bytes(&fl.to_bytes()).unwrap();
+        assert_eq!(restored.list_dir("").unwrap(), ["a", "file"]);
+        assert_eq!(restored.list_dir("a/b").unwrap(), ["file"]);
+        assert!(restored.file_exists("a/b/file"));
+    }
+
+    #[test]
+    fn metadata_of_other_version_is_rejected() {
+        let fl: FileLayer<Vec<u8>> = FileLayer::default();
+        let mut bytes = fl.to_bytes();
+        bytes[4] += 1;
+
+        let result = FileLayer::<Vec<u8>>::from_bytes(&bytes);
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::Unsupported);
+
+        let result = FileLayer::<Vec<u8>>::from_bytes(b"garbage!");
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
 }
@@ -0,0 +1,768 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
+};
+
+use crate::file_layer::{FileHandle, OpenMode};
+use crate::map::Database;
+use crate::storage::DataContainer;
+use crate::system::FileSystem;
+use crate::{ChunkHash, Chunker, Hasher, WriteMeasurements};
+
+/// How long the kernel may cache attributes and entries received from the file system.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Inode of the root directory, as required by FUSE.
+const ROOT_INODE: u64 = 1;
+
+const BLOCK_SIZE: u32 = 4096;
+
+/// File opened through FUSE.
+struct OpenFile<C: Chunker> {
+    handle: FileHandle<C>,
+    /// Whether the file was opened for writing.
+    writable: bool,
+    /// Whether the data is still written sequentially to the end of the file,
+    /// so it can be passed to [`FileSystem::write_to_file`].
+    appending: bool,
+    /// Offset at which the next sequential write is expected.
+    end: usize,
+}
+
+/// Adapter that exposes a [`FileSystem`] through FUSE, so that it can be mounted
+/// and used by regular tools.
+///
+/// Writes to the end of a file are passed to [`FileSystem::write_to_file`],
+/// other writes are done using [`FileSystem::write_at`].
+/// [`WriteMeasurements`] of all closed files are accumulated and can be retrieved
+/// after unmounting, see [`mount`].
+pub struct FuseFileSystem<B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    C: Chunker,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+{
+    fs: FileSystem<B, H, Hash, K>,
+    new_chunker: Box<dyn Fn() -> C>,
+    paths: HashMap<u64, String>,
+    inodes: HashMap<String, u64>,
+    next_inode: u64,
+    open_files: HashMap<u64, OpenFile<C>>,
+    next_handle: u64,
+    measurements: Rc<Cell<WriteMeasurements>>,
+    mount_time: SystemTime,
+}
+
+/// Mounts the file system at `mountpoint` and blocks until it is unmounted.
+///
+/// `new_chunker` is used to create a chunker for every file that is opened.
+/// Returns [`WriteMeasurements`] accumulated over all files written while the file system was mounted.
+pub fn mount<B, H, Hash, K, C>(
+    fs: FileSystem<B, H, Hash, K>,
+    new_chunker: impl Fn() -> C + 'static,
+    mountpoint: impl AsRef<Path>,
+) -> io::Result<WriteMeasurements>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    C: Chunker,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+{
+    let fuse = FuseFileSystem::new(fs, new_chunker);
+    let measurements = fuse.measurements.clone();
+
+    let options = [
+        MountOption::FSName("chunkfs".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(fuse, mountpoint, &options)?;
+
+    Ok(measurements.get())
+}
+
+impl<B, H, Hash, K, C> FuseFileSystem<B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    C: Chunker,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+{
+    pub fn new(fs: FileSystem<B, H, Hash, K>, new_chunker: impl Fn() -> C + 'static) -> Self {
+        Self {
+            fs,
+            new_chunker: Box::new(new_chunker),
+            paths: HashMap::from([(ROOT_INODE, String::new())]),
+            inodes: HashMap::from([(String::new(), ROOT_INODE)]),
+            next_inode: ROOT_INODE + 1,
+            open_files: HashMap::new(),
+            next_handle: 0,
+            measurements: Rc::new(Cell::new(WriteMeasurements::default())),
+            mount_time: SystemTime::now(),
+        }
+    }
+
+    /// Returns [`WriteMeasurements`] accumulated over all closed files.
+    pub fn measurements(&self) -> WriteMeasurements {
+        self.measurements.get()
+    }
+
+    /// Returns path corresponding to the inode.
+    fn path(&self, inode: u64) -> io::Result<String> {
+        self.paths
+            .get(&inode)
+            .cloned()
+            .ok_or(ErrorKind::NotFound.into())
+    }
+
+    /// Returns path of the entry called `name` in the directory with inode `parent`.
+    fn child_path(&self, parent: u64, name: &OsStr) -> io::Result<String> {
+        let parent = self.path(parent)?;
+        let name = name.to_str().ok_or(ErrorKind::InvalidInput)?;
+        if parent.is_empty() {
+            Ok(name.to_string())
+        } else {
+            Ok(format!("{parent}/{name}"))
+        }
+    }
+
+    /// Returns inode of the path, assigning a new one if the path wasn't seen before.
+    fn inode(&mut self, path: &str) -> u64 {
+        if let Some(inode) = self.inodes.get(path) {
+            return *inode;
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.inodes.insert(path.to_string(), inode);
+        self.paths.insert(inode, path.to_string());
+        inode
+    }
+
+    /// Forgets the inode of the removed path.
+    fn forget_path(&mut self, path: &str) {
+        if let Some(inode) = self.inodes.remove(path) {
+            self.paths.remove(&inode);
+        }
+    }
+
+    /// Returns attributes of the file or directory at the given path.
+    fn attr(&mut self, path: &str) -> io::Result<FileAttr> {
+        let (kind, size, perm, nlink) = if self.fs.dir_exists(path) {
+            (FileType::Directory, 0, 0o755, 2)
+        } else {
+            let size = self.fs.file_size(path)? as u64;
+            (FileType::RegularFile, size, 0o644, 1)
+        };
+
+        Ok(FileAttr {
+            ino: self.inode(path),
+            size,
+            blocks: size.div_ceil(BLOCK_SIZE as u64),
+            atime: self.mount_time,
+            mtime: self.mount_time,
+            ctime: self.mount_time,
+            crtime: self.mount_time,
+            kind,
+            perm,
+            nlink,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        })
+    }
+
+    /// Registers an open file and returns its FUSE file handle.
+    fn register(&mut self, handle: FileHandle<C>, writable: bool, end: usize) -> u64 {
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        self.open_files.insert(
+            fh,
+            OpenFile {
+                handle,
+                writable,
+                appending: writable,
+                end,
+            },
+        );
+        fh
+    }
+
+    /// Opens the file at the given path in the mode corresponding to the `flags`.
+    fn open_path(&mut self, path: &str, flags: i32) -> io::Result<u64> {
+        let mode = if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            OpenMode::Read
+        } else if flags & libc::O_TRUNC != 0 {
+            OpenMode::Truncate
+        } else {
+            OpenMode::Append
+        };
+
+        let handle = self.fs.open_file(path, (self.new_chunker)(), mode)?;
+        let end = handle.offset();
+        Ok(self.register(handle, mode != OpenMode::Read, end))
+    }
+
+    /// Changes size of the file, truncating it or extending it with zeroes.
+    fn resize(&mut self, path: &str, size: usize) -> io::Result<()> {
+        let current = self.fs.file_size(path)?;
+        if size <= current {
+            return self.fs.truncate(path, size);
+        }
+
+        let mut handle = self
+            .fs
+            .open_file(path, (self.new_chunker)(), OpenMode::Append)?;
+        self.fs
+            .write_to_file(&mut handle, &vec![0; size - current])?;
+        let measurements = self.fs.close_file(handle)?;
+        self.measurements
+            .set(self.measurements.get() + measurements);
+        Ok(())
+    }
+
+    /// Returns `len` bytes of the open file, starting from `offset`.
+    fn read_open_file(&self, fh: u64, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let open_file = self.open_files.get(&fh).ok_or(ErrorKind::NotFound)?;
+        self.fs.read_at(&open_file.handle, offset, len)
+    }
+
+    /// Writes `data` to the open file at the given `offset`.
+    fn write_open_file(&mut self, fh: u64, offset: usize, data: &[u8]) -> io::Result<()> {
+        let open_file = self.open_files.get_mut(&fh).ok_or(ErrorKind::NotFound)?;
+        if !open_file.writable {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+
+        if open_file.appending && offset == open_file.end {
+            self.fs.write_to_file(&mut open_file.handle, data)?;
+            open_file.end += data.len();
+        } else {
+            open_file.appending = false;
+            self.fs.write_at(&mut open_file.handle, offset, data)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the open file and adds its measurements to the total.
+    fn close_open_file(&mut self, fh: u64) -> io::Result<()> {
+        let open_file = self.open_files.remove(&fh).ok_or(ErrorKind::NotFound)?;
+        let measurements = self.fs.close_file(open_file.handle)?;
+        self.measurements
+            .set(self.measurements.get() + measurements);
+        Ok(())
+    }
+
+    /// Returns FUSE file handles of the files open at the given path.
+    fn handles_of(&self, path: &str) -> Vec<u64> {
+        self.open_files
+            .iter()
+            .filter(|(_, open_file)| open_file.handle.name() == path)
+            .map(|(fh, _)| *fh)
+            .collect()
+    }
+
+    /// Closes all files open at the given path, so that their data is stored before the file is removed.
+    /// Later operations on their FUSE file handles fail with `ErrorKind::NotFound`.
+    fn close_path(&mut self, path: &str) -> io::Result<()> {
+        for fh in self.handles_of(path) {
+            self.close_open_file(fh)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the file at the given path, closing files open at it first.
+    fn unlink_path(&mut self, path: &str) -> io::Result<()> {
+        if !self.fs.file_exists(path) {
+            return Err(ErrorKind::NotFound.into());
+        }
+
+        self.close_path(path)?;
+        self.fs.delete_file(path)?;
+        self.forget_path(path);
+        Ok(())
+    }
+
+    /// Renames the file at `old` to `new`, replacing the file at `new` if it exists.
+    ///
+    /// The replaced file is moved aside and only deleted after the rename succeeds.
+    /// Files open at `old` keep working under the new path, files open at the replaced file are closed.
+    fn rename_path(&mut self, old: &str, new: String) -> io::Result<()> {
+        if old == new {
+            return Ok(());
+        }
+
+        // data held by the chunkers of the open files is stored and journaled under the current names,
+        // so that the handles can be moved to other names
+        let mut open = self.handles_of(old);
+        open.extend(self.handles_of(&new));
+        for fh in open {
+            let open_file = self.open_files.get_mut(&fh).ok_or(ErrorKind::NotFound)?;
+            self.fs.flush_remainder(&mut open_file.handle)?;
+        }
+
+        let replaced = if self.fs.file_exists(&new) {
+            let mut aside = format!("{new}.replaced");
+            while self.fs.file_exists(&aside) || self.fs.dir_exists(&aside) {
+                aside.push('~');
+            }
+            self.fs.rename_file(&new, aside.clone())?;
+            Some(aside)
+        } else {
+            None
+        };
+
+        if let Err(error) = self.fs.rename_file(old, new.clone()) {
+            if let Some(aside) = replaced {
+                self.fs.rename_file(&aside, new)?;
+            }
+            return Err(error);
+        }
+
+        if let Some(aside) = replaced {
+            for fh in self.handles_of(&new) {
+                self.open_files
+                    .get_mut(&fh)
+                    .unwrap()
+                    .handle
+                    .set_name(aside.clone());
+            }
+            self.close_path(&aside)?;
+            self.fs.delete_file(&aside)?;
+            self.forget_path(&new);
+        }
+        for fh in self.handles_of(old) {
+            self.open_files
+                .get_mut(&fh)
+                .unwrap()
+                .handle
+                .set_name(new.clone());
+        }
+        if let Some(inode) = self.inodes.remove(old) {
+            self.inodes.insert(new.clone(), inode);
+            self.paths.insert(inode, new);
+        }
+        Ok(())
+    }
+}
+
+/// Converts an [`io::Error`] to the error code expected by FUSE.
+fn errno(error: io::Error) -> i32 {
+    match error.kind() {
+        ErrorKind::NotFound => libc::ENOENT,
+        ErrorKind::AlreadyExists => libc::EEXIST,
+        ErrorKind::IsADirectory => libc::EISDIR,
+        ErrorKind::NotADirectory => libc::ENOTDIR,
+        ErrorKind::DirectoryNotEmpty => libc::ENOTEMPTY,
+        ErrorKind::InvalidInput => libc::EINVAL,
+        ErrorKind::PermissionDenied => libc::EACCES,
+        ErrorKind::Unsupported => libc::ENOTSUP,
+        _ => libc::EIO,
+    }
+}
+
+impl<B, H, Hash, K, C> Filesystem for FuseFileSystem<B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    C: Chunker,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+{
+    fn destroy(&mut self) {
+        let handles = self.open_files.keys().copied().collect::<Vec<_>>();
+        for fh in handles {
+            let _ = self.close_open_file(fh);
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .child_path(parent, name)
+            .and_then(|path| self.attr(&path))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.path(ino).and_then(|path| self.attr(&path)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    /// Only supports changing size of a file, other attributes are ignored.
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let result = self.path(ino).and_then(|path| {
+            if let Some(size) = size {
+                self.resize(&path, size as usize)?;
+            }
+            self.attr(&path)
+        });
+
+        match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let result = self.child_path(parent, name).and_then(|path| {
+            self.fs.mkdir(&path)?;
+            self.attr(&path)
+        });
+
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self
+            .child_path(parent, name)
+            .and_then(|path| self.unlink_path(&path));
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.child_path(parent, name).and_then(|path| {
+            self.fs.remove_dir(&path)?;
+            self.forget_path(&path);
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    /// Renames a file, replacing the target file if it exists.
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let result = self.child_path(parent, name).and_then(|old| {
+            let new = self.child_path(newparent, newname)?;
+            self.rename_path(&old, new)
+        });
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.path(ino).and_then(|path| self.open_path(&path, flags)) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_open_file(fh, offset as usize, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.write_open_file(fh, offset as usize, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.close_open_file(fh) {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(error) => return reply.error(errno(error)),
+        };
+        let names = match self.fs.list_dir(&path) {
+            Ok(names) => names,
+            Err(error) => return reply.error(errno(error)),
+        };
+
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (ino, FileType::Directory, "..".to_string()),
+        ];
+        for name in names {
+            let child = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{path}/{name}")
+            };
+            let kind = if self.fs.dir_exists(&child) {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+            entries.push((self.inode(&child), kind, name));
+        }
+
+        for (i, (inode, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(inode, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let result = self.child_path(parent, name).and_then(|path| {
+            let handle = self
+                .fs
+                .create_file(path.clone(), (self.new_chunker)(), false)?;
+            let fh = self.register(handle, true, 0);
+            Ok((self.attr(&path)?, fh))
+        });
+
+        match result {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::ErrorKind;
+
+    use crate::chunkers::FSChunker;
+    use crate::fuse::FuseFileSystem;
+    use crate::hashers::SimpleHasher;
+    use crate::storage::DataContainer;
+    use crate::system::FileSystem;
+
+    type TestFuse =
+        FuseFileSystem<HashMap<Vec<u8>, DataContainer<i32>>, SimpleHasher, Vec<u8>, i32, FSChunker>;
+
+    fn fuse_fs() -> TestFuse {
+        let fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+        FuseFileSystem::new(fs, || FSChunker::new(4096))
+    }
+
+    fn create(fuse: &mut TestFuse, path: &str, data: &[u8]) {
+        let handle = fuse
+            .fs
+            .create_file(path.to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        let fh = fuse.register(handle, true, 0);
+        fuse.write_open_file(fh, 0, data).unwrap();
+        fuse.close_open_file(fh).unwrap();
+    }
+
+    fn read(fuse: &mut TestFuse, path: &str) -> Vec<u8> {
+        let size = fuse.fs.file_size(path).unwrap();
+        let fh = fuse.open_path(path, libc::O_RDONLY).unwrap();
+        let data = fuse.read_open_file(fh, 0, size).unwrap();
+        fuse.close_open_file(fh).unwrap();
+        data
+    }
+
+    #[test]
+    fn sequential_and_random_writes_are_stored() {
+        let mut fuse = fuse_fs();
+        create(&mut fuse, "file", &[1; 10_000]);
+
+        let fh = fuse.open_path("file", libc::O_WRONLY).unwrap();
+        fuse.write_open_file(fh, 10_000, &[2; 5000]).unwrap();
+        fuse.write_open_file(fh, 100, &[3; 100]).unwrap();
+        fuse.close_open_file(fh).unwrap();
+
+        let mut expected = vec![1; 10_000];
+        expected.extend([2; 5000]);
+        expected[100..200].fill(3);
+        assert_eq!(read(&mut fuse, "file"), expected);
+        assert!(fuse.open_files.is_empty());
+        assert_eq!(
+            fuse.close_open_file(fh).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn read_only_files_cannot_be_written() {
+        let mut fuse = fuse_fs();
+        create(&mut fuse, "file", &[1; 100]);
+
+        let fh = fuse.open_path("file", libc::O_RDONLY).unwrap();
+        let result = fuse.write_open_file(fh, 100, &[2; 100]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn unlink_closes_open_files() {
+        let mut fuse = fuse_fs();
+        create(&mut fuse, "file", &[1; 100]);
+        let inode = fuse.inode("file");
+
+        let fh = fuse.open_path("file", libc::O_WRONLY).unwrap();
+        fuse.write_open_file(fh, 100, &[2; 100]).unwrap();
+        fuse.unlink_path("file").unwrap();
+
+        assert!(!fuse.fs.file_exists("file"));
+        assert!(fuse.open_files.is_empty());
+        assert!(fuse.path(inode).is_err());
+        assert_eq!(
+            fuse.write_open_file(fh, 200, &[3; 100]).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn open_files_follow_rename() {
+        let mut fuse = fuse_fs();
+        create(&mut fuse, "old", &[1; 10_000]);
+        let inode = fuse.inode("old");
+
+        let fh = fuse.open_path("old", libc::O_WRONLY).unwrap();
+        fuse.write_open_file(fh, 10_000, &[2; 1000]).unwrap();
+        fuse.rename_path("old", "new".to_string()).unwrap();
+        fuse.write_open_file(fh, 11_000, &[3; 1000]).unwrap();
+        fuse.close_open_file(fh).unwrap();
+
+        let mut expected = vec![1; 10_000];
+        expected.extend([2; 1000]);
+        expected.extend([3; 1000]);
+        assert_eq!(read(&mut fuse, "new"), expected);
+        assert!(!fuse.fs.file_exists("old"));
+        assert_eq!(fuse.path(inode).unwrap(), "new");
+    }
+
+    #[test]
+    fn rename_replaces_target_only_after_success() {
+        let mut fuse = fuse_fs();
+        create(&mut fuse, "source", &[1; 5000]);
+        create(&mut fuse, "target", &[2; 5000]);
+        fuse.fs.mkdir("dir").unwrap();
+
+        // the new parent directory doesn't exist, so the rename fails and the target is kept
+        let result = fuse.rename_path("source", "missing/target".to_string());
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+        create(&mut fuse, "dir/target", &[3; 5000]);
+        let result = fuse.rename_path("dir", "dir/target".to_string());
+        assert!(result.is_err());
+        assert_eq!(read(&mut fuse, "dir/target"), [3; 5000]);
+
+        let target = fuse.open_path("target", libc::O_RDONLY).unwrap();
+        fuse.rename_path("source", "target".to_string()).unwrap();
+        assert_eq!(read(&mut fuse, "target"), [1; 5000]);
+        assert_eq!(fuse.fs.list_dir("").unwrap().len(), 2);
+        assert_eq!(
+            fuse.read_open_file(target, 0, 10).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}
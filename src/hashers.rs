@@ -1,9 +1,12 @@
+use std::io;
+use std::io::ErrorKind;
+
 use sha2::digest::Output;
 use sha2::{Digest, Sha256};
 
-use crate::Hasher;
+use crate::{Encode, Hasher};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SimpleHasher;
 
 impl Hasher for SimpleHasher {
@@ -14,7 +17,7 @@ impl Hasher for SimpleHasher {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Sha256Hasher {
     hasher: Sha256,
 }
@@ -27,3 +30,16 @@ impl Hasher for Sha256Hasher {
         Digest::finalize_reset(&mut self.hasher)
     }
 }
+
+impl Encode for Output<Sha256> {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() != Self::default().len() {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        Ok(Self::clone_from_slice(bytes))
+    }
+}
@@ -0,0 +1,360 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::disk::{write_bytes, Cursor};
+use crate::storage::Span;
+use crate::{ChunkHash, Encode};
+
+const CHECKPOINT_RECORD: u8 = 0;
+const CREATE_RECORD: u8 = 1;
+const DELETE_RECORD: u8 = 2;
+const RENAME_RECORD: u8 = 3;
+const MKDIR_RECORD: u8 = 4;
+const REMOVE_DIR_RECORD: u8 = 5;
+const SPLICE_RECORD: u8 = 6;
+const TRUNCATE_RECORD: u8 = 7;
+const SCRUB_STARTED_RECORD: u8 = 8;
+const SCRUB_FINISHED_RECORD: u8 = 9;
+const PENDING_RECORD: u8 = 10;
+const SCRUBBED_RECORD: u8 = 11;
+
+/// Completed operation on the [`FileSystem`][crate::FileSystem], recorded in the [`Journal`].
+#[derive(Debug)]
+pub enum Record<Hash: ChunkHash> {
+    /// Starts the journal. Contains [`checksum`] of the metadata, changes to which are recorded in the journal.
+    Checkpoint(u64),
+    Create(String),
+    Delete(String),
+    Rename(String, String),
+    Mkdir(String),
+    RemoveDir(String),
+    /// Spans of the file in the given range were replaced with the new ones.
+    /// The end of the range may be past the last span, in which case all spans starting from `range.start` were replaced.
+    Splice {
+        name: String,
+        range: Range<usize>,
+        spans: Vec<Span<Hash>>,
+    },
+    /// The file was truncated to `len` bytes. If `len` fell inside a span,
+    /// that span was replaced by the chunk with the `cut` hash.
+    Truncate {
+        name: String,
+        len: usize,
+        cut: Option<Hash>,
+    },
+    ScrubStarted,
+    ScrubFinished,
+    /// Chunks that were pending for an incremental scrub when the metadata was saved, oldest first.
+    Pending(Vec<Span<Hash>>),
+    /// Chunks processed by an incremental scrub.
+    Scrubbed(Vec<Hash>),
+}
+
+impl<Hash: ChunkHash> Record<Hash> {
+    fn to_bytes(&self, encode: fn(&Hash) -> Vec<u8>) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Record::Checkpoint(checksum) => {
+                bytes.push(CHECKPOINT_RECORD);
+                bytes.extend_from_slice(&checksum.to_le_bytes());
+            }
+            Record::Create(name) => {
+                bytes.push(CREATE_RECORD);
+                write_bytes(&mut bytes, name.as_bytes());
+            }
+            Record::Delete(name) => {
+                bytes.push(DELETE_RECORD);
+                write_bytes(&mut bytes, name.as_bytes());
+            }
+            Record::Rename(old, new) => {
+                bytes.push(RENAME_RECORD);
+                write_bytes(&mut bytes, old.as_bytes());
+                write_bytes(&mut bytes, new.as_bytes());
+            }
+            Record::Mkdir(path) => {
+                bytes.push(MKDIR_RECORD);
+                write_bytes(&mut bytes, path.as_bytes());
+            }
+            Record::RemoveDir(path) => {
+                bytes.push(REMOVE_DIR_RECORD);
+                write_bytes(&mut bytes, path.as_bytes());
+            }
+            Record::Splice { name, range, spans } => {
+                bytes.push(SPLICE_RECORD);
+                write_bytes(&mut bytes, name.as_bytes());
+                bytes.extend_from_slice(&(range.start as u64).to_le_bytes());
+                bytes.extend_from_slice(&(range.end as u64).to_le_bytes());
+                write_spans(&mut bytes, spans, encode);
+            }
+            Record::Truncate { name, len, cut } => {
+                bytes.push(TRUNCATE_RECORD);
+                write_bytes(&mut bytes, name.as_bytes());
+                bytes.extend_from_slice(&(*len as u64).to_le_bytes());
+                match cut {
+                    Some(hash) => {
+                        bytes.push(1);
+                        write_bytes(&mut bytes, &encode(hash));
+                    }
+                    None => bytes.push(0),
+                }
+            }
+            Record::ScrubStarted => bytes.push(SCRUB_STARTED_RECORD),
+            Record::ScrubFinished => bytes.push(SCRUB_FINISHED_RECORD),
+            Record::Pending(spans) => {
+                bytes.push(PENDING_RECORD);
+                write_spans(&mut bytes, spans, encode);
+            }
+            Record::Scrubbed(hashes) => {
+                bytes.push(SCRUBBED_RECORD);
+                bytes.extend_from_slice(&(hashes.len() as u32).to_le_bytes());
+                for hash in hashes {
+                    write_bytes(&mut bytes, &encode(hash));
+                }
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self>
+    where
+        Hash: Encode,
+    {
+        let mut cursor = Cursor::new(bytes);
+        let record = match cursor.u8()? {
+            CHECKPOINT_RECORD => Record::Checkpoint(cursor.u64()?),
+            CREATE_RECORD => Record::Create(read_string(&mut cursor)?),
+            DELETE_RECORD => Record::Delete(read_string(&mut cursor)?),
+            RENAME_RECORD => Record::Rename(read_string(&mut cursor)?, read_string(&mut cursor)?),
+            MKDIR_RECORD => Record::Mkdir(read_string(&mut cursor)?),
+            REMOVE_DIR_RECORD => Record::RemoveDir(read_string(&mut cursor)?),
+            SPLICE_RECORD => {
+                let name = read_string(&mut cursor)?;
+                let range = read_usize(&mut cursor)?..read_usize(&mut cursor)?;
+                let spans = read_spans(&mut cursor)?;
+                Record::Splice { name, range, spans }
+            }
+            TRUNCATE_RECORD => {
+                let name = read_string(&mut cursor)?;
+                let len = read_usize(&mut cursor)?;
+                let cut = match cursor.u8()? {
+                    0 => None,
+                    _ => Some(Hash::from_bytes(cursor.bytes()?)?),
+                };
+                Record::Truncate { name, len, cut }
+            }
+            SCRUB_STARTED_RECORD => Record::ScrubStarted,
+            SCRUB_FINISHED_RECORD => Record::ScrubFinished,
+            PENDING_RECORD => Record::Pending(read_spans(&mut cursor)?),
+            SCRUBBED_RECORD => Record::Scrubbed(
+                (0..cursor.u32()?)
+                    .map(|_| Hash::from_bytes(cursor.bytes()?))
+                    .collect::<io::Result<_>>()?,
+            ),
+            _ => return Err(ErrorKind::InvalidData.into()),
+        };
+
+        if !cursor.is_empty() {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        Ok(record)
+    }
+}
+
+/// Append-only log of the operations on the [`FileSystem`][crate::FileSystem], made after its metadata was saved.
+///
+/// Each record is stored as its length, its bytes and their [`checksum`], and is synced to disk once appended,
+/// so that a record interrupted by a crash can be told apart from the complete ones.
+pub struct Journal<Hash> {
+    file: File,
+    encode: fn(&Hash) -> Vec<u8>,
+}
+
+impl<Hash: ChunkHash + Encode> Journal<Hash> {
+    /// Creates an empty journal at `path`, replacing the existing one,
+    /// which records changes to the metadata with the given checksum.
+    pub fn create(path: &Path, checkpoint: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut journal = Self {
+            file,
+            encode: Hash::to_bytes,
+        };
+        journal.append(&Record::Checkpoint(checkpoint))?;
+        Ok(journal)
+    }
+
+    /// Opens the journal at `path` to append new records after the first `len` bytes,
+    /// discarding everything that follows them.
+    pub fn open(path: &Path, len: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_data()?;
+
+        Ok(Self {
+            file,
+            encode: Hash::to_bytes,
+        })
+    }
+
+    /// Reads the records from the journal at `path`, stopping at the first incomplete or damaged one.
+    ///
+    /// Returns the records and the length of the part of the journal they occupy.
+    pub fn read(path: &Path) -> io::Result<(Vec<Record<Hash>>, u64)> {
+        let bytes = std::fs::read(path)?;
+        let mut cursor = Cursor::new(&bytes);
+        let mut records = vec![];
+        let mut len = 0;
+
+        while let Ok(record) = read_framed(&mut cursor) {
+            records.push(Record::from_bytes(record)?);
+            len += (record.len() + 12) as u64;
+        }
+
+        Ok((records, len))
+    }
+}
+
+impl<Hash: ChunkHash> Journal<Hash> {
+    /// Appends the record to the journal and syncs it to disk.
+    pub fn append(&mut self, record: &Record<Hash>) -> io::Result<()> {
+        let record = record.to_bytes(self.encode);
+
+        let mut bytes = Vec::with_capacity(record.len() + 12);
+        bytes.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&record);
+        bytes.extend_from_slice(&checksum(&record).to_le_bytes());
+
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()
+    }
+}
+
+/// Returns path of the journal that belongs to the metadata file at `metadata`.
+pub fn journal_path(metadata: &Path) -> PathBuf {
+    let mut path = metadata.as_os_str().to_owned();
+    path.push(".journal");
+    path.into()
+}
+
+/// FNV-1a hash of the bytes, used to detect damaged records and to match the journal with the metadata.
+pub fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Reads a record with its length and checksum, returning an error if it is incomplete or damaged.
+fn read_framed<'a>(cursor: &mut Cursor<'a>) -> io::Result<&'a [u8]> {
+    let record = cursor.bytes()?;
+    if cursor.u64()? != checksum(record) {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    Ok(record)
+}
+
+fn write_spans<Hash: ChunkHash>(
+    bytes: &mut Vec<u8>,
+    spans: &[Span<Hash>],
+    encode: fn(&Hash) -> Vec<u8>,
+) {
+    bytes.extend_from_slice(&(spans.len() as u32).to_le_bytes());
+    for span in spans {
+        write_bytes(bytes, &encode(&span.hash));
+        bytes.extend_from_slice(&(span.length as u64).to_le_bytes());
+    }
+}
+
+fn read_spans<Hash: ChunkHash + Encode>(cursor: &mut Cursor) -> io::Result<Vec<Span<Hash>>> {
+    (0..cursor.u32()?)
+        .map(|_| {
+            let hash = Hash::from_bytes(cursor.bytes()?)?;
+            Ok(Span::new(hash, read_usize(cursor)?))
+        })
+        .collect()
+}
+
+fn read_string(cursor: &mut Cursor) -> io::Result<String> {
+    String::from_utf8(cursor.bytes()?.to_vec()).map_err(|_| ErrorKind::InvalidData.into())
+}
+
+fn read_usize(cursor: &mut Cursor) -> io::Result<usize> {
+    Ok(usize::try_from(cursor.u64()?).unwrap_or(usize::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::journal::{journal_path, Journal, Record};
+    use crate::storage::Span;
+
+    fn temp_path() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("chunkfs-journal-{}-{id}", std::process::id()))
+    }
+
+    #[test]
+    fn records_are_read_back() {
+        let path = temp_path();
+        let mut journal = Journal::<Vec<u8>>::create(&path, 42).unwrap();
+        journal.append(&Record::Create("file".to_string())).unwrap();
+        journal
+            .append(&Record::Splice {
+                name: "file".to_string(),
+                range: 0..usize::MAX,
+                spans: vec![Span::new(vec![1, 2, 3], 3)],
+            })
+            .unwrap();
+
+        let (records, len) = Journal::<Vec<u8>>::read(&path).unwrap();
+        assert_eq!(len, std::fs::metadata(&path).unwrap().len());
+        assert!(matches!(records[0], Record::Checkpoint(42)));
+        assert!(matches!(&records[1], Record::Create(name) if name == "file"));
+        assert!(matches!(
+            &records[2],
+            Record::Splice { range, spans, .. } if *range == (0..usize::MAX) && spans[0].hash == [1, 2, 3]
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn incomplete_record_is_discarded() {
+        let path = temp_path();
+        let mut journal = Journal::<Vec<u8>>::create(&path, 0).unwrap();
+        journal.append(&Record::Mkdir("dir".to_string())).unwrap();
+        let (_, complete) = Journal::<Vec<u8>>::read(&path).unwrap();
+        journal.append(&Record::Delete("file".to_string())).unwrap();
+        drop(journal);
+
+        let full = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full - 3).unwrap();
+
+        let (records, len) = Journal::<Vec<u8>>::read(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(len, complete);
+
+        let mut journal = Journal::<Vec<u8>>::open(&path, len).unwrap();
+        journal.append(&Record::ScrubStarted).unwrap();
+        let (records, _) = Journal::<Vec<u8>>::read(&path).unwrap();
+        assert!(matches!(records[2], Record::ScrubStarted));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn journal_path_is_next_to_metadata() {
+        let path = journal_path(&PathBuf::from("/tmp/fs/metadata"));
+        assert_eq!(path, PathBuf::from("/tmp/fs/metadata.journal"));
+    }
+}
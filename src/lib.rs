@@ -1,21 +1,47 @@
 use std::hash;
+use std::io;
 use std::ops::{Add, AddAssign};
 use std::time::Duration;
 
+pub use background::{BackgroundScrubber, ScrubPolicy};
+pub use delta::DeltaScrubber;
+pub use disk::{DiskDatabase, DiskTargetMap, Encode};
+pub use file_layer::OpenMode;
 pub use map::Database;
-pub use scrub::{Scrub, ScrubMeasurements};
+pub use scrub::{Scrub, ScrubBudget, ScrubMeasurements, SubChunkScrubber};
+pub use shared::SharedFileSystem;
+pub use stats::FileSystemStats;
 pub use storage::{Data, DataContainer};
+pub use stream::FileStream;
 pub use system::FileSystem;
 
 #[cfg(feature = "chunkers")]
 pub mod chunkers;
+// SimpleCompressor is always available, the LZ4 and Zstandard compressors require the feature
+pub mod compressors;
+#[cfg(feature = "fuse")]
+pub mod fuse;
 #[cfg(feature = "hashers")]
 pub mod hashers;
 
+// the test data helpers are shared with the integration tests
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+mod background;
+mod delta;
+mod disk;
 mod file_layer;
+mod journal;
 mod map;
+mod parallel;
+mod pipeline;
 mod scrub;
+mod shared;
+mod stats;
 mod storage;
+mod stream;
 mod system;
 
 /// Trait for a CDC hash, combining several other traits: [hash::Hash], [Clone], [Eq], [PartialEq], [Default].
@@ -25,9 +51,9 @@ pub trait ChunkHash: hash::Hash + Clone + Eq + PartialEq + Default {}
 
 impl<T: hash::Hash + Clone + Eq + PartialEq + Default> ChunkHash for T {}
 
-/// Block size, used by [`read`][crate::FileSystem::read_from_file]
+/// Default block size, used by [`read`][crate::FileSystem::read_from_file]
 /// and [`write`][crate::FileSystem::write_to_file] methods in the [`FileSystem`].
-/// Blocks given to the user or by them must be of this size.
+/// Can be changed with [`set_segment_size`][crate::FileSystem::set_segment_size].
 const SEG_SIZE: usize = 1024 * 1024; // 1MB
 
 /// A chunk of the processed data. Doesn't store any data,
@@ -92,12 +118,41 @@ pub trait Hasher {
     fn hash(&mut self, data: &[u8]) -> Self::Hash;
 }
 
+/// Functionality for an object that compresses chunks before they are stored,
+/// and decompresses them when they are retrieved.
+pub trait Compressor {
+    /// Takes some `data` and returns it compressed.
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Takes data returned by [compress][Compressor::compress] and restores the original data.
+    ///
+    /// # Errors
+    /// Should return [ErrorKind::InvalidData][io::ErrorKind::InvalidData], if the data is corrupted.
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
 /// Measurements that are received after writing data to a file.
-/// Contain time spent for chunking and for hashing.
+///
+/// Contain time spent on each stage of the write: assembling the buffer from the chunker's remainder and new data,
+/// chunking, hashing, copying new chunks out of the buffer, and looking them up and inserting them into the database.
+/// Hashing time is measured both as wall time and as time spent by all hashing threads.
+/// If chunks are compressed, also contain time spent on compression and the compressed size of the new chunks.
+/// Also contain amounts of written bytes and chunks, and how many of the bytes were new to the storage
+/// or duplicated already stored chunks.
 #[derive(Debug, PartialEq, Default, Clone, Copy)]
 pub struct WriteMeasurements {
+    buffer_time: Duration,
     chunk_time: Duration,
     hash_time: Duration,
+    hash_cpu_time: Duration,
+    copy_time: Duration,
+    compress_time: Duration,
+    insert_time: Duration,
+    written_bytes: usize,
+    chunk_count: usize,
+    new_bytes: usize,
+    compressed_bytes: usize,
+    duplicate_bytes: usize,
 }
 
 impl WriteMeasurements {
@@ -105,32 +160,163 @@ impl WriteMeasurements {
         Self {
             chunk_time,
             hash_time,
+            hash_cpu_time: hash_time,
+            ..Default::default()
         }
     }
 
+    /// Time spent on assembling the buffer that is given to the chunker.
+    pub fn buffer_time(&self) -> Duration {
+        self.buffer_time
+    }
+
     pub fn chunk_time(&self) -> Duration {
         self.chunk_time
     }
 
+    /// Wall time spent on hashing the chunks.
     pub fn hash_time(&self) -> Duration {
         self.hash_time
     }
+
+    /// Time spent on hashing the chunks, summed over all hashing threads.
+    /// Equal to [`hash_time`][WriteMeasurements::hash_time] if the chunks are hashed on a single thread.
+    pub fn hash_cpu_time(&self) -> Duration {
+        self.hash_cpu_time
+    }
+
+    /// Time spent on collecting new chunks from the buffer for insertion.
+    /// Chunks are borrowed from the buffer, copying them, if the database needs to, is counted in the insertion time.
+    pub fn copy_time(&self) -> Duration {
+        self.copy_time
+    }
+
+    /// Time spent on compressing new chunks.
+    pub fn compress_time(&self) -> Duration {
+        self.compress_time
+    }
+
+    /// Time spent on looking chunks up in the database and inserting the new ones.
+    pub fn insert_time(&self) -> Duration {
+        self.insert_time
+    }
+
+    /// Amount of bytes in all produced chunks, both new and duplicate.
+    pub fn written_bytes(&self) -> usize {
+        self.written_bytes
+    }
+
+    /// Amount of produced chunks, both new and duplicate.
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_count
+    }
+
+    /// Amount of bytes in chunks that were not stored before and were inserted into the database.
+    pub fn new_bytes(&self) -> usize {
+        self.new_bytes
+    }
+
+    /// Amount of bytes that the new chunks take in the database after compression.
+    /// Equal to [`new_bytes`][WriteMeasurements::new_bytes], if chunks are not compressed.
+    pub fn compressed_bytes(&self) -> usize {
+        self.compressed_bytes
+    }
+
+    /// Amount of bytes in chunks that were already stored, and thus were not inserted again.
+    pub fn duplicate_bytes(&self) -> usize {
+        self.duplicate_bytes
+    }
 }
 
 impl Add for WriteMeasurements {
     type Output = Self;
 
-    fn add(self, rhs: Self) -> Self::Output {
-        Self {
-            chunk_time: self.chunk_time + rhs.chunk_time,
-            hash_time: self.hash_time + rhs.hash_time,
-        }
+    fn add(mut self, rhs: Self) -> Self::Output {
+        self += rhs;
+        self
     }
 }
 
 impl AddAssign for WriteMeasurements {
     fn add_assign(&mut self, rhs: Self) {
+        self.buffer_time += rhs.buffer_time;
         self.chunk_time += rhs.chunk_time;
         self.hash_time += rhs.hash_time;
+        self.hash_cpu_time += rhs.hash_cpu_time;
+        self.copy_time += rhs.copy_time;
+        self.compress_time += rhs.compress_time;
+        self.insert_time += rhs.insert_time;
+        self.written_bytes += rhs.written_bytes;
+        self.chunk_count += rhs.chunk_count;
+        self.new_bytes += rhs.new_bytes;
+        self.compressed_bytes += rhs.compressed_bytes;
+        self.duplicate_bytes += rhs.duplicate_bytes;
+    }
+}
+
+/// Measurements that are received after reading data from a file.
+///
+/// Contain time spent on finding hashes of the requested spans in the file layer, retrieving chunks from the database,
+/// restoring scrubbed chunks from the target map, decompressing chunks, and concatenating the chunks into the result,
+/// along with the amount of bytes read.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct ReadMeasurements {
+    lookup_time: Duration,
+    retrieve_time: Duration,
+    reconstruct_time: Duration,
+    decompress_time: Duration,
+    concat_time: Duration,
+    read_bytes: usize,
+}
+
+impl ReadMeasurements {
+    /// Time spent on finding hashes of the spans in the file layer.
+    pub fn lookup_time(&self) -> Duration {
+        self.lookup_time
+    }
+
+    /// Time spent on retrieving chunks from the database.
+    pub fn retrieve_time(&self) -> Duration {
+        self.retrieve_time
+    }
+
+    /// Time spent on restoring scrubbed chunks from the target map.
+    pub fn reconstruct_time(&self) -> Duration {
+        self.reconstruct_time
+    }
+
+    /// Time spent on decompressing chunks.
+    pub fn decompress_time(&self) -> Duration {
+        self.decompress_time
+    }
+
+    /// Time spent on concatenating retrieved chunks and cutting them to the requested range.
+    pub fn concat_time(&self) -> Duration {
+        self.concat_time
+    }
+
+    /// Amount of bytes returned by the reads.
+    pub fn read_bytes(&self) -> usize {
+        self.read_bytes
+    }
+}
+
+impl Add for ReadMeasurements {
+    type Output = Self;
+
+    fn add(mut self, rhs: Self) -> Self::Output {
+        self += rhs;
+        self
+    }
+}
+
+impl AddAssign for ReadMeasurements {
+    fn add_assign(&mut self, rhs: Self) {
+        self.lookup_time += rhs.lookup_time;
+        self.retrieve_time += rhs.retrieve_time;
+        self.reconstruct_time += rhs.reconstruct_time;
+        self.decompress_time += rhs.decompress_time;
+        self.concat_time += rhs.concat_time;
+        self.read_bytes += rhs.read_bytes;
     }
 }
@@ -27,6 +27,21 @@ pub trait Database<K, V> {
         Ok(())
     }
 
+    /// Inserts multiple values, given as data borrowed from a larger buffer, into the storage.
+    /// Allows the storage to write the data directly, without creating a value for each piece of it first.
+    ///
+    /// By default, each value is created from its data, and all of them are inserted with [insert_multi][Database::insert_multi].
+    fn insert_multi_borrowed(&mut self, pairs: Vec<(K, &[u8])>) -> io::Result<()>
+    where
+        V: for<'a> From<&'a [u8]>,
+    {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, data)| (key, V::from(data)))
+            .collect();
+        self.insert_multi(pairs)
+    }
+
     /// Retrieves a multitude of values, corresponding to the keys, in the correct order.
     fn get_multi(&self, keys: &[K]) -> io::Result<Vec<V>> {
         keys.iter().map(|key| self.get(key)).collect()
@@ -34,6 +49,31 @@ pub trait Database<K, V> {
 
     /// Returns `true` if the database contains a value for the specified key.
     fn contains(&self, key: &K) -> bool;
+
+    /// Calls `f` with every key-value pair in the storage, allowing it to change the values.
+    /// Stops at the first error returned by `f`.
+    ///
+    /// By default, the storage's mutable iterator is used. Storages that keep values on disk
+    /// can override it to avoid holding all of them in memory at once.
+    fn for_each_mut<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        Self: Sized,
+        F: FnMut(&K, &mut V) -> io::Result<()>,
+        for<'a> &'a mut Self: IntoIterator<Item = (&'a K, &'a mut V)>,
+    {
+        for (key, value) in self {
+            f(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Persists changes made to the values since the last flush,
+    /// including the ones made through the database's iterator.
+    ///
+    /// Does nothing by default, which is suitable for in-memory databases.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl<Hash: ChunkHash, V: Clone> Database<Hash, V> for HashMap<Hash, V> {
@@ -0,0 +1,185 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Chunk, Hasher};
+
+/// Hashes chunks of the written data, possibly on several threads.
+pub(crate) trait HashChunks<Hash> {
+    /// Returns hashes of the `chunks` of `data` in the same order as the chunks,
+    /// along with the time spent on hashing, summed over all threads.
+    fn hash_chunks(&mut self, data: &[u8], chunks: &[Chunk]) -> (Vec<Hash>, Duration);
+}
+
+/// Work passed to the hashing threads: chunks of a buffer, which get hashed.
+pub(crate) trait HashJob<Hash> {
+    /// Returns the buffer and its chunks that should be hashed.
+    fn chunks(&self) -> (&[u8], &[Chunk]);
+
+    /// Stores hashes of the chunks and the time spent on hashing them.
+    fn set_hashes(&mut self, hashes: Vec<Hash>, time: Duration);
+}
+
+/// Runs a hashing thread of a pool: takes jobs from the receiver shared by the pool,
+/// hashes them and sends them back, until either of the channels is closed.
+pub(crate) fn run_hash_worker<H, J>(
+    hasher: &mut H,
+    jobs: &Mutex<Receiver<J>>,
+    hashed: &SyncSender<J>,
+) where
+    H: Hasher,
+    J: HashJob<H::Hash>,
+{
+    loop {
+        // the lock is released before hashing, so that other threads can take the next jobs
+        let received = jobs.lock().unwrap().recv();
+        let Ok(mut job) = received else {
+            break;
+        };
+
+        let start = Instant::now();
+        let (buffer, chunks) = job.chunks();
+        let hashes = chunks
+            .iter()
+            .map(|chunk| hasher.hash(&buffer[chunk.range()]))
+            .collect();
+        job.set_hashes(hashes, start.elapsed());
+
+        if hashed.send(job).is_err() {
+            break;
+        }
+    }
+}
+
+/// Group of chunks hashed by one of the threads of a [`ParallelHasher`].
+struct Group<Hash> {
+    index: usize,
+    data: Arc<[u8]>,
+    chunks: Vec<Chunk>,
+    hashes: Vec<Hash>,
+    time: Duration,
+}
+
+impl<Hash> HashJob<Hash> for Group<Hash> {
+    fn chunks(&self) -> (&[u8], &[Chunk]) {
+        (&self.data, &self.chunks)
+    }
+
+    fn set_hashes(&mut self, hashes: Vec<Hash>, time: Duration) {
+        self.hashes = hashes;
+        self.time = time;
+    }
+}
+
+/// Hashes chunks on a pool of threads, each of which has its own [`Hasher`].
+///
+/// Chunks are split into contiguous groups, one per thread, so that the order of the hashes stays the same
+/// as with a single hasher. The threads are started once and live as long as the parallel hasher,
+/// taking the groups from a shared channel, the same way as the hashing stage of the write pipeline.
+pub(crate) struct ParallelHasher<Hash> {
+    groups: Option<SyncSender<Group<Hash>>>,
+    hashed: Receiver<Group<Hash>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<Hash: Send + 'static> ParallelHasher<Hash> {
+    /// Creates a parallel hasher with `threads` copies of the given `hasher`.
+    pub fn new<H>(hasher: &H, threads: usize) -> Self
+    where
+        H: Hasher<Hash = Hash> + Clone + Send + 'static,
+    {
+        let (groups, group_receiver) = sync_channel(threads);
+        let (hashed_sender, hashed) = sync_channel(threads);
+
+        let group_receiver = Arc::new(Mutex::new(group_receiver));
+        let workers = (0..threads)
+            .map(|_| {
+                let mut hasher = hasher.clone();
+                let group_receiver = group_receiver.clone();
+                let hashed_sender = hashed_sender.clone();
+                thread::spawn(move || run_hash_worker(&mut hasher, &group_receiver, &hashed_sender))
+            })
+            .collect();
+
+        Self {
+            groups: Some(groups),
+            hashed,
+            workers,
+        }
+    }
+}
+
+impl<Hash> HashChunks<Hash> for ParallelHasher<Hash> {
+    fn hash_chunks(&mut self, data: &[u8], chunks: &[Chunk]) -> (Vec<Hash>, Duration) {
+        if chunks.is_empty() {
+            return (vec![], Duration::default());
+        }
+
+        let data = Arc::<[u8]>::from(data);
+        let group_size = chunks.len().div_ceil(self.workers.len());
+        let sender = self.groups.as_ref().unwrap();
+        let mut count = 0;
+        for (index, group) in chunks.chunks(group_size).enumerate() {
+            let group = Group {
+                index,
+                data: data.clone(),
+                chunks: group.to_vec(),
+                hashes: vec![],
+                time: Duration::default(),
+            };
+            sender.send(group).expect("hashing threads stopped");
+            count += 1;
+        }
+
+        let mut groups = (0..count)
+            .map(|_| self.hashed.recv().expect("hashing threads stopped"))
+            .collect::<Vec<_>>();
+        groups.sort_by_key(|group| group.index);
+
+        let cpu_time = groups.iter().map(|group| group.time).sum();
+        let hashes = groups.into_iter().flat_map(|group| group.hashes).collect();
+        (hashes, cpu_time)
+    }
+}
+
+impl<Hash> Drop for ParallelHasher<Hash> {
+    /// Closes the channel of the groups, which stops the threads, and waits for them.
+    fn drop(&mut self) {
+        self.groups = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hashers::Sha256Hasher;
+    use crate::{Chunk, Hasher};
+
+    use super::{HashChunks, ParallelHasher};
+
+    #[test]
+    fn hashes_are_in_order_of_chunks() {
+        let data = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let chunks = (0..100)
+            .map(|i| Chunk::new(i * 100, 100))
+            .collect::<Vec<_>>();
+
+        let mut hasher = Sha256Hasher::default();
+        let expected = chunks
+            .iter()
+            .map(|chunk| hasher.hash(&data[chunk.range()]))
+            .collect::<Vec<_>>();
+
+        for threads in [1, 3, 8, 200] {
+            let mut parallel = ParallelHasher::new(&hasher, threads);
+            // the same threads are used for every call
+            for _ in 0..3 {
+                let (hashes, _) = parallel.hash_chunks(&data, &chunks);
+                assert_eq!(hashes, expected, "{threads} threads");
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::borrow::Cow;
+use std::io;
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::parallel::{run_hash_worker, HashJob};
+use crate::storage::{chunk_segment, SpansInfo};
+use crate::{Chunk, Chunker, Hasher, WriteMeasurements};
+
+/// Segment of the written data, passed between the stages of the pipeline.
+struct Segment<'a, Hash> {
+    index: usize,
+    buffer: Cow<'a, [u8]>,
+    chunks: Vec<Chunk>,
+    hashes: Vec<Hash>,
+    measurements: WriteMeasurements,
+}
+
+impl<Hash> HashJob<Hash> for Segment<'_, Hash> {
+    fn chunks(&self) -> (&[u8], &[Chunk]) {
+        (&self.buffer, &self.chunks)
+    }
+
+    fn set_hashes(&mut self, hashes: Vec<Hash>, time: Duration) {
+        self.hashes = hashes;
+        self.measurements.hash_time = time;
+        self.measurements.hash_cpu_time = time;
+    }
+}
+
+/// Writes `data` in segments of `segment_size` bytes,
+/// with chunking, hashing and storing of different segments running at the same time.
+///
+/// The chunker runs on its own thread, segments are hashed by a pool of `threads` copies of the `hasher`,
+/// running the same workers as a [`ParallelHasher`][crate::parallel::ParallelHasher],
+/// and passed to `store` on the calling thread in their original order, so that the resulting spans are the same
+/// as if the segments were written one after another. Channels between the stages are bounded,
+/// so that only a few segments are held in memory at once.
+pub(crate) fn write_pipelined<C, H, S>(
+    data: &[u8],
+    segment_size: usize,
+    chunker: &mut C,
+    continued: bool,
+    hasher: &H,
+    threads: usize,
+    mut store: S,
+) -> io::Result<SpansInfo<H::Hash>>
+where
+    C: Chunker + Send,
+    H: Hasher + Clone + Send,
+    H::Hash: Send,
+    S: FnMut(&[u8], &[Chunk], Vec<H::Hash>, WriteMeasurements) -> io::Result<SpansInfo<H::Hash>>,
+{
+    thread::scope(|scope| {
+        let (chunked_sender, chunked_receiver) = sync_channel::<Segment<H::Hash>>(threads);
+        let (hashed_sender, hashed_receiver) = sync_channel(threads);
+
+        scope.spawn(move || {
+            let mut continued = continued;
+            for (index, data) in data.chunks(segment_size).enumerate() {
+                let (buffer, chunks, measurements) = chunk_segment(chunker, data, continued);
+                continued = true;
+
+                let segment = Segment {
+                    index,
+                    buffer,
+                    chunks,
+                    hashes: vec![],
+                    measurements,
+                };
+                // the receiving stages are gone if storing failed
+                if chunked_sender.send(segment).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // the receiver is dropped once all hashing threads stop, which also stops the chunker
+        let chunked_receiver = Arc::new(Mutex::new(chunked_receiver));
+        for _ in 0..threads {
+            let mut hasher = hasher.clone();
+            let chunked_receiver = chunked_receiver.clone();
+            let hashed_sender = hashed_sender.clone();
+            scope.spawn(move || run_hash_worker(&mut hasher, &chunked_receiver, &hashed_sender));
+        }
+        drop(chunked_receiver);
+        drop(hashed_sender);
+
+        // segments are hashed in any order, and are stored once all the preceding ones are
+        let mut info = SpansInfo {
+            spans: vec![],
+            measurements: Default::default(),
+        };
+        let mut pending = vec![];
+        let mut next = 0;
+        for segment in hashed_receiver {
+            pending.push(segment);
+            while let Some(position) = pending.iter().position(|segment| segment.index == next) {
+                let segment = pending.swap_remove(position);
+                let stored = store(
+                    &segment.buffer,
+                    &segment.chunks,
+                    segment.hashes,
+                    segment.measurements,
+                )?;
+                info.spans.extend(stored.spans);
+                info.measurements += stored.measurements;
+                next += 1;
+            }
+        }
+        Ok(info)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::chunkers::FSChunker;
+    use crate::hashers::Sha256Hasher;
+    use crate::scrub::DumbScrubber;
+    use crate::storage::{store_chunks, ChunkStorage, DataContainer};
+    use crate::Chunker;
+
+    use super::write_pipelined;
+
+    #[test]
+    fn pipelined_spans_match_serial_ones() {
+        let data = (0..300_000).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        let hasher = Sha256Hasher::default();
+
+        let mut serial = ChunkStorage::new(
+            HashMap::<_, DataContainer<()>>::new(),
+            Box::new(HashMap::new()),
+            Box::new(DumbScrubber),
+            hasher.clone(),
+        );
+        let mut chunker = FSChunker::new(3000);
+        let mut expected = vec![];
+        for (i, segment) in data.chunks(10_000).enumerate() {
+            let info = serial.write(segment, &mut chunker, i > 0).unwrap();
+            expected.extend(info.spans.into_iter().map(|span| span.hash));
+        }
+        let remainder = chunker.remainder().to_vec();
+
+        for threads in [1, 2, 5] {
+            let mut base = HashMap::<_, DataContainer<()>>::new();
+            let mut chunker = FSChunker::new(3000);
+            let info = write_pipelined(
+                &data,
+                10_000,
+                &mut chunker,
+                false,
+                &hasher,
+                threads,
+                |buffer, chunks, hashes, measurements| {
+                    store_chunks(buffer, chunks, hashes, measurements, None, &mut base)
+                },
+            )
+            .unwrap();
+
+            let hashes = info
+                .spans
+                .into_iter()
+                .map(|span| span.hash)
+                .collect::<Vec<_>>();
+            assert_eq!(hashes, expected, "{threads} threads");
+            assert_eq!(chunker.remainder(), remainder);
+            assert_eq!(
+                info.measurements.written_bytes(),
+                data.len() - remainder.len()
+            );
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use std::io;
-use std::time::Duration;
+use std::io::ErrorKind;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
 
 use crate::map::Database;
-use crate::storage::DataContainer;
-use crate::ChunkHash;
+use crate::storage::{Data, DataContainer};
+use crate::{ChunkHash, Chunker, Hasher};
 
 /// Basic functionality for implementing algorithms which process chunks provided by the [Chunker][crate::Chunker]. The implementations should encapsulate
 /// algorithm logic (write part) inside themselves and not delegate it to `database`. The read part of the algorithm should be encapsulated in `target_map`.
@@ -19,8 +21,11 @@ use crate::ChunkHash;
 /// Therefore, after moving, we should leave a `Vec<Key>` in place of the source chunk. It is done via [DataContainer::make_target] method.
 /// Not using it will lead to either not getting any benefits from the algorithm, or to being unable to access the initial chunk anymore, if it was deleted.
 ///
+/// Scrubbers that process each chunk independently of the others should also implement [scrub_chunk][Scrub::scrub_chunk],
+/// which allows to scrub only the chunks written since the last scrub, see [FileSystem::scrub_incremental][crate::FileSystem::scrub_incremental].
+///
 /// # Arguments
-/// The only method [scrub][Scrub::scrub] takes two arguments:
+/// The main method [scrub][Scrub::scrub] takes two arguments:
 /// 1. A CDC [Database], which contains `Hash`-[`DataContainer`] pairs. To access the underlying data in the container,
 /// [DataContainer::extract] or [DataContainer::extract_mut] should be used.
 ///
@@ -33,8 +38,9 @@ where
     for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<Key>)>,
 {
     /// # How to implement
-    /// To iterate over the underlying chunks, `database.into_iter()` should be used.
-    /// It will automatically yield pairs, which consist of `&mut Hash` and `&mut DataContainer`. To access the underlying data in the container,
+    /// To go over the underlying chunks, [Database::for_each_mut] should be used.
+    /// It calls the given closure with pairs, which consist of `&Hash` and `&mut DataContainer`, and lets databases
+    /// that keep chunks on disk hold only one of them in memory at a time. To access the underlying data in the container,
     /// [DataContainer::extract] or [DataContainer::extract_mut] should be used.
     ///
     /// If the chunk is suitable for being transferred to the `target_map`, it should NOT be deleted, but instead be replaced by the `target_map`'s keys,
@@ -52,24 +58,44 @@ where
     /// the target map implements [Database] trait.
     ///
     /// # CDC Database
-    /// We should be able to iterate over the `database` to process all chunks we had stored before.
-    /// The [IntoIterator] trait should be implemented for `database`, but it should not be a big concern, because the only structure that should be implemented
+    /// We should be able to go over the `database` with [Database::for_each_mut] to process all chunks we had stored before.
+    /// The [IntoIterator] trait, used by its default implementation, should be implemented for `database`, but it should not be a big concern, because the only structure that should be implemented
     /// for the algorithm is the scrubber itself. `database` should be considered a given entity, along with the `target_map`.
     fn scrub<'a>(
         &mut self,
         database: &mut B,
-        target_map: &mut Box<dyn Database<Key, Vec<u8>>>,
+        target_map: &mut Box<dyn Database<Key, Vec<u8>> + Send>,
     ) -> io::Result<ScrubMeasurements>
     where
         Hash: 'a,
         Key: 'a;
+
+    /// Processes a single chunk, which is stored in the `container`, in the same way as [scrub][Scrub::scrub]
+    /// processes each chunk of the database, adding the processed, untouched and saved data to `measurements`.
+    ///
+    /// The container is written back to the database after the call. It is only called for containers
+    /// that hold a chunk, i.e., were not processed before.
+    ///
+    /// If the file system compresses chunks, this is also used instead of [scrub][Scrub::scrub],
+    /// with the chunk decompressed in the container.
+    ///
+    /// Returns `ErrorKind::Unsupported` by default, in which case only the whole database of uncompressed chunks can be scrubbed.
+    fn scrub_chunk(
+        &mut self,
+        _container: &mut DataContainer<Key>,
+        _target_map: &mut Box<dyn Database<Key, Vec<u8>> + Send>,
+        _measurements: &mut ScrubMeasurements,
+    ) -> io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
 }
 
 /// Measurements made by the scrubber.
 ///
 /// Contains information about the amount of data processed by the scrubber (in bytes),
 /// time spent on scrubbing,
-/// and the amount of data left untouched.
+/// the amount of data left untouched,
+/// and the amount of space saved by the scrubber.
 #[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
 pub struct ScrubMeasurements {
     /// How much data was processed by the scrubber (in bytes).
@@ -78,6 +104,49 @@ pub struct ScrubMeasurements {
     pub running_time: Duration,
     /// The amount of data left untouched (in bytes).
     pub data_left: usize,
+    /// How much less data is stored after scrubbing (in bytes).
+    pub saved_data: usize,
+    /// Amount of chunks written since the last scrub that are not processed yet,
+    /// because the [budget][ScrubBudget] of an incremental scrub ran out.
+    pub pending_chunks: usize,
+    /// Total size of the pending chunks (in bytes).
+    pub pending_data: usize,
+}
+
+impl AddAssign for ScrubMeasurements {
+    /// Adds up measurements of consecutive scrubs. Pending chunks and data are taken from the later scrub.
+    fn add_assign(&mut self, rhs: Self) {
+        self.processed_data += rhs.processed_data;
+        self.running_time += rhs.running_time;
+        self.data_left += rhs.data_left;
+        self.saved_data += rhs.saved_data;
+        self.pending_chunks = rhs.pending_chunks;
+        self.pending_data = rhs.pending_data;
+    }
+}
+
+/// Limit on the work done by a single [incremental scrub][crate::FileSystem::scrub_incremental].
+///
+/// At least one chunk is processed by each scrub, so that scrubbing always makes progress.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ScrubBudget {
+    /// All chunks written since the last scrub are processed.
+    Unlimited,
+    /// Chunks are processed until the given time runs out.
+    Time(Duration),
+    /// Chunks are processed until the given amount of data (in bytes) is processed.
+    Bytes(usize),
+}
+
+impl ScrubBudget {
+    /// Returns `true` if the budget is used up after scrubbing `bytes` of data in `elapsed` time.
+    pub(crate) fn is_exhausted(&self, elapsed: Duration, bytes: usize) -> bool {
+        match self {
+            ScrubBudget::Unlimited => false,
+            ScrubBudget::Time(time) => elapsed >= *time,
+            ScrubBudget::Bytes(limit) => bytes >= *limit,
+        }
+    }
 }
 
 pub struct DumbScrubber;
@@ -90,7 +159,7 @@ where
     fn scrub<'a>(
         &mut self,
         _database: &mut B,
-        _target: &mut Box<dyn Database<Key, Vec<u8>>>,
+        _target: &mut Box<dyn Database<Key, Vec<u8>> + Send>,
     ) -> io::Result<ScrubMeasurements>
     where
         Hash: 'a,
@@ -98,4 +167,96 @@ where
     {
         Ok(ScrubMeasurements::default())
     }
+
+    fn scrub_chunk(
+        &mut self,
+        _container: &mut DataContainer<Key>,
+        _target_map: &mut Box<dyn Database<Key, Vec<u8>> + Send>,
+        _measurements: &mut ScrubMeasurements,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Scrubber that splits chunks into smaller sub-chunks with its own chunker, to find duplicates
+/// that are missed by the larger chunks made when the data is written.
+///
+/// Sub-chunks are stored in the target map, keyed by their hashes, so that each distinct sub-chunk is stored once.
+/// Each chunk is replaced with the hashes of its sub-chunks.
+pub struct SubChunkScrubber<C: Chunker, H: Hasher> {
+    chunker: C,
+    hasher: H,
+}
+
+impl<C: Chunker, H: Hasher> SubChunkScrubber<C, H> {
+    /// Creates a scrubber that splits chunks with the `chunker` and hashes the sub-chunks with the `hasher`.
+    pub fn new(chunker: C, hasher: H) -> Self {
+        Self { chunker, hasher }
+    }
+}
+
+impl<Hash: ChunkHash, B, C, H> Scrub<Hash, B, H::Hash> for SubChunkScrubber<C, H>
+where
+    B: Database<Hash, DataContainer<H::Hash>>,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<H::Hash>)>,
+    C: Chunker,
+    H: Hasher,
+{
+    fn scrub<'a>(
+        &mut self,
+        database: &mut B,
+        target_map: &mut Box<dyn Database<H::Hash, Vec<u8>> + Send>,
+    ) -> io::Result<ScrubMeasurements>
+    where
+        Hash: 'a,
+        H::Hash: 'a,
+    {
+        let start = Instant::now();
+        let mut measurements = ScrubMeasurements::default();
+        database.for_each_mut(|_, container| {
+            self.scrub_chunk(container, target_map, &mut measurements)
+        })?;
+
+        measurements.running_time = start.elapsed();
+        Ok(measurements)
+    }
+
+    fn scrub_chunk(
+        &mut self,
+        container: &mut DataContainer<H::Hash>,
+        target_map: &mut Box<dyn Database<H::Hash, Vec<u8>> + Send>,
+        measurements: &mut ScrubMeasurements,
+    ) -> io::Result<()> {
+        let Data::Chunk(chunk) = container.extract() else {
+            return Ok(());
+        };
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        measurements.processed_data += chunk.len();
+
+        let empty = Vec::with_capacity(self.chunker.estimate_chunk_count(chunk));
+        let mut sub_chunks = self
+            .chunker
+            .chunk_data(chunk, empty)
+            .into_iter()
+            .map(|sub_chunk| &chunk[sub_chunk.range()])
+            .collect::<Vec<_>>();
+        if !self.chunker.remainder().is_empty() {
+            sub_chunks.push(self.chunker.remainder());
+        }
+
+        let mut keys = Vec::with_capacity(sub_chunks.len());
+        for sub_chunk in sub_chunks {
+            let hash = self.hasher.hash(sub_chunk);
+            if target_map.contains(&hash) {
+                measurements.saved_data += sub_chunk.len();
+            } else {
+                target_map.insert(hash.clone(), sub_chunk.to_vec())?;
+            }
+            keys.push(hash);
+        }
+        container.make_target(keys);
+        Ok(())
+    }
 }
@@ -0,0 +1,149 @@
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::file_layer::{FileHandle, OpenMode};
+use crate::map::Database;
+use crate::storage::{chunk_segment, DataContainer};
+use crate::system::{concat_range, FileSystem};
+use crate::{ChunkHash, Chunker, Hasher, ReadMeasurements};
+
+/// [`FileSystem`] that can be shared between threads, so that several files can be written and read at the same time.
+///
+/// Cloning it is cheap, all clones refer to the same file system. The file system is kept behind a lock,
+/// which is only held while the file layer and the database are accessed: data written through
+/// [`write_to_file`][SharedFileSystem::write_to_file] is chunked and hashed on the calling thread before taking it,
+/// and chunks read through [`read_at`][SharedFileSystem::read_at] are concatenated after releasing it.
+/// All other operations are available through [`lock`][SharedFileSystem::lock].
+///
+/// The file layer and the database share a single lock, so storing and retrieving chunks,
+/// as well as all operations done through [`lock`][SharedFileSystem::lock], are not parallel between threads,
+/// even if they concern different files.
+///
+/// Each thread uses its own [`FileHandle`]s. Data written through one handle can be read through other handles
+/// to the same file once it is stored: the end of the file that is held by the writing handle's chunker
+/// is stored when the handle is closed. Only one handle should write to a file at a time.
+pub struct SharedFileSystem<B, H, Hash, K>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+{
+    fs: Arc<Mutex<FileSystem<B, H, Hash, K>>>,
+}
+
+impl<B, H, Hash, K> Clone for SharedFileSystem<B, H, Hash, K>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            fs: self.fs.clone(),
+        }
+    }
+}
+
+impl<B, H, Hash, K> SharedFileSystem<B, H, Hash, K>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+{
+    /// Puts the file system behind a lock, so that it can be shared.
+    pub fn new(fs: FileSystem<B, H, Hash, K>) -> Self {
+        Self {
+            fs: Arc::new(Mutex::new(fs)),
+        }
+    }
+
+    /// Locks the file system, so that any of its operations can be used.
+    /// Other threads wait for the returned guard to be dropped.
+    ///
+    /// # Panics
+    /// Panics if another thread panicked while holding the lock.
+    pub fn lock(&self) -> MutexGuard<'_, FileSystem<B, H, Hash, K>> {
+        self.fs.lock().unwrap()
+    }
+
+    /// Writes given data to the end of the file. Segments of the data are chunked and hashed without holding the lock,
+    /// using a copy of the file system's hasher.
+    ///
+    /// Chunks are hashed on the calling thread, the amount of threads set by
+    /// [`set_hash_threads`][FileSystem::set_hash_threads] is not used.
+    /// Returns `ErrorKind::PermissionDenied`, if the file was opened for reading.
+    pub fn write_to_file<C: Chunker>(
+        &self,
+        handle: &mut FileHandle<C>,
+        data: &[u8],
+    ) -> io::Result<()>
+    where
+        H: Clone,
+    {
+        if handle.mode() == OpenMode::Read {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+
+        let (segment_size, mut hasher) = {
+            let mut fs = self.lock();
+            // the chunker has to continue from the last span of the file before chunking without the lock
+            fs.restore_remainder(handle)?;
+            (fs.segment_size(), fs.hasher().clone())
+        };
+
+        for segment in data.chunks(segment_size) {
+            let (buffer, chunks, mut measurements) =
+                chunk_segment(&mut handle.chunker, segment, handle.remainder_pending);
+
+            let start = Instant::now();
+            let hashes = chunks
+                .iter()
+                .map(|chunk| hasher.hash(&buffer[chunk.range()]))
+                .collect();
+            measurements.hash_time = start.elapsed();
+            measurements.hash_cpu_time = measurements.hash_time;
+
+            self.lock()
+                .write_hashed(handle, &buffer, &chunks, hashes, measurements)?;
+        }
+        Ok(())
+    }
+
+    /// Reads at most `len` bytes of the file, starting at `offset`, and returns them.
+    /// See [`FileSystem::read_at`].
+    pub fn read_at<C: Chunker>(
+        &self,
+        handle: &FileHandle<C>,
+        offset: usize,
+        len: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut measurements = ReadMeasurements::default();
+        let (first, chunks) = self
+            .lock()
+            .retrieve_range(handle, offset, len, &mut measurements)?;
+
+        let data = concat_range(chunks, offset - first, len, &mut measurements);
+        handle.add_read_measurements(measurements);
+        Ok(data)
+    }
+
+    /// Reads a [segment][FileSystem::segment_size] of data from a file, starting at the handle's offset,
+    /// and returns it. The handle's offset is moved past the read data.
+    pub fn read_from_file<C: Chunker>(&self, handle: &mut FileHandle<C>) -> io::Result<Vec<u8>> {
+        let segment_size = self.lock().segment_size();
+        let data = self.read_at(handle, handle.offset(), segment_size)?;
+        handle.seek(handle.offset() + data.len());
+        Ok(data)
+    }
+
+    /// Reads all contents of the file that are stored, from beginning to end, and returns them.
+    pub fn read_file_complete<C: Chunker>(&self, handle: &FileHandle<C>) -> io::Result<Vec<u8>> {
+        self.read_at(handle, 0, usize::MAX)
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+/// Deduplication statistics of the [`FileSystem`][crate::FileSystem], returned by its
+/// [`stats`][crate::FileSystem::stats] method.
+///
+/// Chunk sizes are computed over distinct chunks referenced by files.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct FileSystemStats {
+    /// Total size of all files (in bytes).
+    pub logical_bytes: usize,
+    /// Total size of the chunks stored in the database (in bytes), not counting the ones moved to the target map.
+    /// Compressed chunks are counted with their compressed size.
+    pub unique_bytes: usize,
+    /// Total size of the target map data that the scrubbed chunks are restored from (in bytes).
+    /// Data shared by several scrubbed chunks, e.g. sub-chunks or bases of delta-encoded chunks, is counted once.
+    pub target_bytes: usize,
+    /// Amount of chunks stored in the database.
+    pub chunk_count: usize,
+    /// Amount of spans in all files.
+    pub span_count: usize,
+    /// Amount of spans that point to a chunk which is already pointed to by another span.
+    pub duplicate_chunks: usize,
+    pub avg_chunk_size: f64,
+    pub min_chunk_size: usize,
+    pub max_chunk_size: usize,
+    /// Amount of chunks by size. Sizes are grouped into power-of-two buckets,
+    /// each bucket is keyed by its lower bound, e.g., chunks of 4096..8192 bytes are counted under 4096.
+    pub chunk_size_histogram: BTreeMap<usize, usize>,
+}
+
+impl FileSystemStats {
+    /// Returns the ratio of the size of the files to the size of the stored data.
+    /// Returns `1.0` if nothing is stored.
+    pub fn dedup_ratio(&self) -> f64 {
+        let stored = self.unique_bytes + self.target_bytes;
+        match stored {
+            0 => 1.0,
+            _ => self.logical_bytes as f64 / stored as f64,
+        }
+    }
+
+    /// Fills chunk size statistics from the sizes of distinct chunks.
+    pub(crate) fn add_chunk_sizes(&mut self, sizes: impl IntoIterator<Item = usize>) {
+        let mut count = 0;
+        let mut total = 0;
+        for size in sizes {
+            if count == 0 || size < self.min_chunk_size {
+                self.min_chunk_size = size;
+            }
+            self.max_chunk_size = self.max_chunk_size.max(size);
+            *self.chunk_size_histogram.entry(bucket(size)).or_default() += 1;
+
+            count += 1;
+            total += size;
+        }
+
+        if count > 0 {
+            self.avg_chunk_size = total as f64 / count as f64;
+        }
+    }
+}
+
+/// Returns the lower bound of the power-of-two bucket that contains `size`.
+fn bucket(size: usize) -> usize {
+    match size {
+        0 => 0,
+        _ => 1 << size.ilog2(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::FileSystemStats;
+
+    #[test]
+    fn chunk_sizes_are_grouped_by_power_of_two() {
+        let mut stats = FileSystemStats::default();
+        stats.add_chunk_sizes([4096, 5000, 8191, 8192, 100]);
+
+        assert_eq!(stats.min_chunk_size, 100);
+        assert_eq!(stats.max_chunk_size, 8192);
+        assert_eq!(stats.avg_chunk_size, 5115.8);
+        let histogram: Vec<_> = stats.chunk_size_histogram.into_iter().collect();
+        assert_eq!(histogram, [(64, 1), (4096, 3), (8192, 1)]);
+    }
+}
@@ -1,11 +1,20 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Formatter;
 use std::io;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use crate::delta::apply_delta;
 use crate::map::Database;
-use crate::scrub::{Scrub, ScrubMeasurements};
-use crate::WriteMeasurements;
-use crate::{ChunkHash, Chunker, Hasher};
+use crate::parallel::{HashChunks, ParallelHasher};
+use crate::pipeline::write_pipelined;
+use crate::scrub::{Scrub, ScrubBudget, ScrubMeasurements};
+use crate::stats::FileSystemStats;
+use crate::{Chunk, ChunkHash, Chunker, Compressor, Hasher};
+use crate::{ReadMeasurements, WriteMeasurements};
 
 /// Container for storage data.
 #[derive(Clone, Debug, Default)]
@@ -15,11 +24,18 @@ pub struct DataContainer<K>(Data<K>);
 #[derive(Clone)]
 pub enum Data<K> {
     Chunk(Vec<u8>),
+    /// The chunk is the concatenation of the data stored under the keys.
     TargetChunk(Vec<K>),
+    /// The chunk is restored by applying the delta, stored under the `delta` keys,
+    /// to the base, stored under the `base` keys, as produced by the [`DeltaScrubber`][crate::DeltaScrubber].
+    DeltaChunk {
+        base: Vec<K>,
+        delta: Vec<K>,
+    },
 }
 
 /// Hashed span in a [`file`][crate::file_layer::File] with a certain length.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Span<Hash: ChunkHash> {
     pub hash: Hash,
     pub length: usize,
@@ -38,6 +54,43 @@ impl<Hash: ChunkHash> Span<Hash> {
     }
 }
 
+/// Amount of data pending for an incremental scrub and time of the last write to a [`ChunkStorage`],
+/// which are shared with threads that decide when to scrub it without locking it.
+#[derive(Debug)]
+pub struct WriteActivity {
+    created: Instant,
+    /// Time of the last write, in nanoseconds since `created`.
+    last_write: AtomicU64,
+    /// Total length of the chunks that are pending for an incremental scrub.
+    pending_bytes: AtomicUsize,
+}
+
+impl WriteActivity {
+    fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            last_write: AtomicU64::new(0),
+            pending_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns total length of the chunks stored since the last scrub that are not scrubbed yet.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns time passed since chunks were last written to the storage, or since it was created.
+    pub fn idle_time(&self) -> Duration {
+        let last_write = Duration::from_nanos(self.last_write.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last_write)
+    }
+
+    fn touch(&self) {
+        let elapsed = self.created.elapsed().as_nanos() as u64;
+        self.last_write.store(elapsed, Ordering::Relaxed);
+    }
+}
+
 /// Underlying storage for the actual stored data.
 pub struct ChunkStorage<H, Hash, B, K>
 where
@@ -47,22 +100,41 @@ where
     for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
 {
     database: B,
-    scrubber: Box<dyn Scrub<Hash, B, K>>,
-    target_map: Box<dyn Database<K, Vec<u8>>>,
+    scrubber: Box<dyn Scrub<Hash, B, K> + Send>,
+    target_map: Box<dyn Database<K, Vec<u8>> + Send>,
     hasher: H,
+    /// Hasher used instead of `hasher` to hash chunks of the written segments on several threads.
+    parallel_hasher: Option<Box<dyn HashChunks<Hash> + Send>>,
+    /// Amount of threads that hash chunks of the written data.
+    hash_threads: usize,
+    /// Compressor applied to new chunks before they are stored, and reversed when they are retrieved.
+    compressor: Option<Box<dyn Compressor + Send>>,
+    /// Amount of file spans referencing each stored chunk.
+    ref_counts: HashMap<Hash, usize>,
+    /// Amount of stored chunks referring to each key of the target map.
+    /// Data of a key is removed from the target map once no chunk refers to it.
+    target_ref_counts: HashMap<K, usize>,
+    /// Hashes of the chunks stored since the last scrub, in the order they were stored.
+    /// Incremental scrubs process them from the front, skipping the ones that are no longer pending.
+    pending: VecDeque<Hash>,
+    /// Lengths of the pending chunks. Chunks stop being pending once they are scrubbed or removed from the database.
+    pending_lengths: HashMap<Hash, usize>,
+    /// Total length of the pending chunks and time of the last write.
+    activity: Arc<WriteActivity>,
 }
 
 impl<H, Hash, B, K> ChunkStorage<H, Hash, B, K>
 where
     H: Hasher<Hash = Hash>,
     Hash: ChunkHash,
+    K: ChunkHash,
     B: Database<H::Hash, DataContainer<K>>,
     for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
 {
     pub fn new(
         database: B,
-        target_map: Box<dyn Database<K, Vec<u8>>>,
-        scrubber: Box<dyn Scrub<Hash, B, K>>,
+        target_map: Box<dyn Database<K, Vec<u8>> + Send>,
+        scrubber: Box<dyn Scrub<Hash, B, K> + Send>,
         hasher: H,
     ) -> Self {
         Self {
@@ -70,15 +142,246 @@ where
             scrubber,
             target_map,
             hasher,
+            parallel_hasher: None,
+            hash_threads: 1,
+            compressor: None,
+            ref_counts: HashMap::new(),
+            target_ref_counts: HashMap::new(),
+            pending: VecDeque::new(),
+            pending_lengths: HashMap::new(),
+            activity: Arc::new(WriteActivity::new()),
         }
     }
 
+    /// Makes the storage compress new chunks with the given `compressor` before inserting them into the database.
+    ///
+    /// Chunks that are already stored are not compressed, so it should be set before any data is written.
+    /// Chunks are decompressed before they are scrubbed, and the target map keeps the scrubbed data uncompressed.
+    pub fn set_compressor(&mut self, compressor: impl Compressor + Send + 'static) {
+        self.compressor = Some(Box::new(compressor));
+    }
+
+    /// Makes the storage hash chunks of the written segments on the given amount of `threads`,
+    /// each with its own copy of the hasher. A single thread hashes the chunks on the calling thread.
+    pub fn set_hash_threads(&mut self, threads: usize)
+    where
+        H: Clone + Send + 'static,
+        Hash: Send + 'static,
+    {
+        self.hash_threads = threads.max(1);
+        self.parallel_hasher = match threads {
+            0 | 1 => None,
+            _ => Some(Box::new(ParallelHasher::new(&self.hasher, threads))),
+        };
+    }
+
+    /// Scrubs all chunks of the database.
+    ///
+    /// If chunks are compressed, each of them is decompressed and passed to [scrub_chunk][Scrub::scrub_chunk],
+    /// so `ErrorKind::Unsupported` is returned, if the scrubber doesn't support scrubbing single chunks.
     pub fn scrub(&mut self) -> io::Result<ScrubMeasurements> {
-        self.scrubber
-            .scrub(&mut self.database, &mut self.target_map)
+        let measurements = match self.compressor.as_deref() {
+            None => {
+                let measurements = self
+                    .scrubber
+                    .scrub(&mut self.database, &mut self.target_map)?;
+                self.count_target_refs()?;
+                measurements
+            }
+            Some(compressor) => {
+                let start = Instant::now();
+                let mut measurements = ScrubMeasurements::default();
+                self.database.for_each_mut(|_, container| {
+                    let scrubbed = scrub_container(
+                        self.scrubber.as_mut(),
+                        &mut self.target_map,
+                        Some(compressor),
+                        container,
+                        &mut measurements,
+                    )?;
+                    if scrubbed {
+                        acquire_targets(&mut self.target_ref_counts, container);
+                    }
+                    Ok(())
+                })?;
+                measurements.running_time = start.elapsed();
+                measurements
+            }
+        };
+        self.sync()?;
+        self.pending.clear();
+        self.pending_lengths.clear();
+        self.activity.pending_bytes.store(0, Ordering::Relaxed);
+        Ok(measurements)
+    }
+
+    /// Scrubs the chunks stored since the last scrub, oldest first, until the `budget` runs out.
+    /// The next incremental scrub continues from the first chunk that was not processed.
+    ///
+    /// Returns the measurements along with the hashes of the processed chunks.
+    ///
+    /// Returns `ErrorKind::Unsupported`, if the scrubber doesn't support [scrubbing single chunks][Scrub::scrub_chunk].
+    pub fn scrub_incremental(
+        &mut self,
+        budget: ScrubBudget,
+    ) -> io::Result<(ScrubMeasurements, Vec<Hash>)> {
+        let start = Instant::now();
+        let mut measurements = ScrubMeasurements::default();
+        let mut processed = vec![];
+        let mut scrubbed_bytes = 0;
+        while let Some(hash) = self.pending.front().cloned() {
+            // chunks that were removed since they were stored, or stored again after that, are no longer pending here
+            let Some(&length) = self.pending_lengths.get(&hash) else {
+                self.pending.pop_front();
+                continue;
+            };
+
+            let mut container = self.database.get(&hash)?;
+            let scrubbed = scrub_container(
+                self.scrubber.as_mut(),
+                &mut self.target_map,
+                self.compressor.as_deref(),
+                &mut container,
+                &mut measurements,
+            )?;
+            if scrubbed {
+                acquire_targets(&mut self.target_ref_counts, &container);
+                self.database.insert(hash.clone(), container)?;
+            }
+
+            scrubbed_bytes += length;
+            self.activity
+                .pending_bytes
+                .fetch_sub(length, Ordering::Relaxed);
+            self.pending_lengths.remove(&hash);
+            self.pending.pop_front();
+            processed.push(hash);
+            if budget.is_exhausted(start.elapsed(), scrubbed_bytes) {
+                break;
+            }
+        }
+        if !processed.is_empty() {
+            self.sync()?;
+        }
+
+        measurements.running_time = start.elapsed();
+        measurements.pending_chunks = self.pending_lengths.len();
+        measurements.pending_data = self.activity.pending_bytes();
+        Ok((measurements, processed))
+    }
+
+    /// Persists changes made to the database and the target map.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.database.flush()?;
+        self.target_map.flush()
     }
 
-    /// Writes 1 MB of data to the [`base`][crate::base::Base] storage after deduplication.
+    /// Counts chunks stored in the database and their sizes, including the data they refer to in the target map.
+    /// Target map data shared by several chunks is counted once.
+    pub fn collect_stats(&mut self, stats: &mut FileSystemStats) -> io::Result<()> {
+        let mut visited = HashSet::new();
+        self.database.for_each_mut(|_, container| {
+            stats.chunk_count += 1;
+            if let Data::Chunk(chunk) = container.extract() {
+                stats.unique_bytes += chunk.len();
+            }
+            for key in container.target_keys() {
+                if visited.insert(key.clone()) {
+                    stats.target_bytes += self.target_map.get(key)?.len();
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes chunks that are not referenced by any span from the database,
+    /// and restores reference counts of the target map keys that the remaining chunks refer to.
+    ///
+    /// Returns `ErrorKind::InvalidData`, if a referenced chunk was scrubbed, but its data is missing from the target map,
+    /// e.g. because the target map is kept only in memory.
+    pub fn collect_garbage(&mut self) -> io::Result<()> {
+        let mut unreferenced = vec![];
+        let mut target_ref_counts = HashMap::new();
+        self.database.for_each_mut(|hash, container| {
+            if !self.ref_counts.contains_key(hash) {
+                unreferenced.push(hash.clone());
+                return Ok(());
+            }
+
+            if !container
+                .target_keys()
+                .all(|key| self.target_map.contains(key))
+            {
+                return Err(ErrorKind::InvalidData.into());
+            }
+            acquire_targets(&mut target_ref_counts, container);
+            Ok(())
+        })?;
+        self.target_ref_counts = target_ref_counts;
+
+        for hash in &unreferenced {
+            self.database.remove(hash);
+        }
+        self.sync()
+    }
+
+    /// Counts references of the stored chunks to the keys of the target map, after the scrubber changed them.
+    fn count_target_refs(&mut self) -> io::Result<()> {
+        let mut target_ref_counts = HashMap::new();
+        self.database.for_each_mut(|_, container| {
+            acquire_targets(&mut target_ref_counts, container);
+            Ok(())
+        })?;
+        self.target_ref_counts = target_ref_counts;
+        Ok(())
+    }
+
+    /// Restores reference counts of the chunks from the hashes of all spans that point to them.
+    /// None of the chunks are pending for an incremental scrub afterwards, see [restore_pending][ChunkStorage::restore_pending].
+    ///
+    /// Returns `ErrorKind::InvalidData`, if some of the chunks are not present in the database.
+    pub fn restore<'a>(&mut self, hashes: impl IntoIterator<Item = &'a Hash>) -> io::Result<()>
+    where
+        Hash: 'a,
+    {
+        for hash in hashes {
+            if !self.database.contains(hash) {
+                return Err(ErrorKind::InvalidData.into());
+            }
+            *self.ref_counts.entry(hash.clone()).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Makes the given chunks pending for an incremental scrub, in the given order.
+    /// Chunks that are not referenced, or were already moved to the target map, are skipped.
+    pub fn restore_pending(&mut self, spans: Vec<Span<Hash>>) -> io::Result<()> {
+        for span in spans {
+            if !self.ref_counts.contains_key(&span.hash) {
+                continue;
+            }
+            if let Data::Chunk(_) = self.database.get(&span.hash)?.extract() {
+                self.push_pending(span.hash, span.length);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns hashes and lengths of the chunks pending for an incremental scrub, oldest first.
+    pub fn pending(&self) -> Vec<Span<Hash>> {
+        self.pending
+            .iter()
+            .filter_map(|hash| {
+                let length = self.pending_lengths.get(hash)?;
+                Some(Span::new(hash.clone(), *length))
+            })
+            .collect()
+    }
+
+    /// Writes a segment of data to the [`base`][crate::base::Base] storage after deduplication.
+    ///
+    /// If `continued` is `true`, the data is treated as a continuation of the previous write,
+    /// and the chunker's remainder is chunked along with it. Otherwise, chunking starts anew from `data`.
     ///
     /// Returns resulting lengths of [chunks][crate::chunker::Chunk] with corresponding hash,
     /// along with amount of time spent on chunking and hashing.
@@ -86,32 +389,246 @@ where
         &mut self,
         data: &[u8],
         chunker: &mut C,
+        continued: bool,
     ) -> io::Result<SpansInfo<H::Hash>> {
         let mut writer = StorageWriter::new(chunker, &mut self.hasher);
-        writer.write(data, &mut self.database)
+        writer.parallel_hasher = self.parallel_hasher.as_mut();
+        writer.compressor = self.compressor.as_deref();
+        let info = writer.write(data, continued, &mut self.database)?;
+        self.acquire(&info.spans);
+        Ok(info)
+    }
+
+    /// Writes `data` in segments of `segment_size` bytes, chunking, hashing and storing different segments
+    /// at the same time. Produces the same spans as [`write`][ChunkStorage::write] called for each segment.
+    ///
+    /// Segments are hashed by as many threads as set by [`set_hash_threads`][ChunkStorage::set_hash_threads].
+    pub fn write_pipelined<C>(
+        &mut self,
+        data: &[u8],
+        segment_size: usize,
+        chunker: &mut C,
+        continued: bool,
+    ) -> io::Result<SpansInfo<H::Hash>>
+    where
+        C: Chunker + Send,
+        H: Clone + Send,
+        Hash: Send,
+    {
+        let compressor = self.compressor.as_deref();
+        let database = &mut self.database;
+        let info = write_pipelined(
+            data,
+            segment_size,
+            chunker,
+            continued,
+            &self.hasher,
+            self.hash_threads,
+            |buffer, chunks, hashes, measurements| {
+                store_chunks(buffer, chunks, hashes, measurements, compressor, database)
+            },
+        )?;
+        self.acquire(&info.spans);
+        Ok(info)
+    }
+
+    /// Stores `chunks` of the `buffer`, which were already chunked and hashed, and returns their spans.
+    pub fn write_hashed(
+        &mut self,
+        buffer: &[u8],
+        chunks: &[Chunk],
+        hashes: Vec<Hash>,
+        measurements: WriteMeasurements,
+    ) -> io::Result<SpansInfo<Hash>> {
+        let info = store_chunks(
+            buffer,
+            chunks,
+            hashes,
+            measurements,
+            self.compressor.as_deref(),
+            &mut self.database,
+        )?;
+        self.acquire(&info.spans);
+        Ok(info)
+    }
+
+    /// Returns the hasher that is used to hash written data.
+    pub fn hasher(&self) -> &H {
+        &self.hasher
     }
 
     /// Flushes remaining data to the storage and returns its [`span`][Span] with hashing and chunking times.
     pub fn flush<C: Chunker>(&mut self, chunker: &mut C) -> io::Result<SpansInfo<H::Hash>> {
         let mut writer = StorageWriter::new(chunker, &mut self.hasher);
-        writer.flush(&mut self.database)
+        writer.compressor = self.compressor.as_deref();
+        let info = writer.flush(&mut self.database)?;
+        self.acquire(&info.spans);
+        Ok(info)
+    }
+
+    /// Stores `data` as a single chunk and returns its [`span`][Span].
+    pub fn store(&mut self, data: Vec<u8>) -> io::Result<Span<Hash>> {
+        let hash = self.hasher.hash(&data);
+        let span = Span::new(hash.clone(), data.len());
+
+        if !self.database.contains(&hash) {
+            let data = match &self.compressor {
+                Some(compressor) => compressor.compress(&data),
+                None => data,
+            };
+            self.database
+                .insert(hash, DataContainer(Data::Chunk(data)))?;
+        }
+        self.acquire(std::slice::from_ref(&span));
+        Ok(span)
+    }
+
+    /// Returns total length of the chunks stored since the last scrub that are not scrubbed yet.
+    pub fn pending_bytes(&self) -> usize {
+        self.activity.pending_bytes()
+    }
+
+    /// Returns time passed since chunks were last written to the storage, or since it was created.
+    pub fn idle_time(&self) -> Duration {
+        self.activity.idle_time()
+    }
+
+    /// Returns the counters of pending data and idle time, which can be read without access to the storage.
+    pub fn activity(&self) -> Arc<WriteActivity> {
+        self.activity.clone()
+    }
+
+    /// Increments reference counts of the chunks that the given spans point to.
+    fn acquire(&mut self, spans: &[Span<Hash>]) {
+        self.activity.touch();
+        for span in spans {
+            self.acquire_one(&span.hash, span.length);
+        }
+    }
+
+    /// Increments the reference count of the chunk. Chunks that were not referenced before are new,
+    /// so they are added to the pending ones.
+    fn acquire_one(&mut self, hash: &Hash, length: usize) {
+        let count = self.ref_counts.entry(hash.clone()).or_default();
+        *count += 1;
+        if *count == 1 {
+            self.push_pending(hash.clone(), length);
+        }
+    }
+
+    /// Adds the chunk to the back of the pending ones, unless it is pending already.
+    fn push_pending(&mut self, hash: Hash, length: usize) {
+        if self.pending_lengths.insert(hash.clone(), length).is_none() {
+            self.pending.push_back(hash);
+            self.activity
+                .pending_bytes
+                .fetch_add(length, Ordering::Relaxed);
+        }
+    }
+
+    /// Stops the removed chunk from being pending. Its hash is dropped from the queue later,
+    /// once the queue mostly consists of such hashes.
+    fn remove_pending(&mut self, hash: &Hash) {
+        let Some(length) = self.pending_lengths.remove(hash) else {
+            return;
+        };
+        self.activity
+            .pending_bytes
+            .fetch_sub(length, Ordering::Relaxed);
+
+        if self.pending.len() > 2 * self.pending_lengths.len() {
+            let mut queued = HashSet::new();
+            self.pending.retain(|hash| {
+                self.pending_lengths.contains_key(hash) && queued.insert(hash.clone())
+            });
+        }
+    }
+
+    /// Decrements reference counts of the chunks with the given hashes.
+    ///
+    /// Chunks that are no longer referenced by any span are removed from the database.
+    pub fn release(&mut self, hashes: &[Hash]) {
+        for hash in hashes {
+            let Some(count) = self.ref_counts.get_mut(hash) else {
+                continue;
+            };
+
+            *count -= 1;
+            if *count == 0 {
+                self.ref_counts.remove(hash);
+                self.remove_pending(hash);
+                // the container is only read if some of the chunks were scrubbed
+                if !self.target_ref_counts.is_empty() {
+                    if let Ok(container) = self.database.get(hash) {
+                        self.release_targets(&container);
+                    }
+                }
+                self.database.remove(hash);
+            }
+        }
+    }
+
+    /// Decreases reference counts of the target map keys the container refers to,
+    /// and removes the data of the keys that are no longer referenced from the target map.
+    fn release_targets(&mut self, container: &DataContainer<K>) {
+        for key in container.target_keys() {
+            let Some(count) = self.target_ref_counts.get_mut(key) else {
+                continue;
+            };
+
+            *count -= 1;
+            if *count == 0 {
+                self.target_ref_counts.remove(key);
+                self.target_map.remove(key);
+            }
+        }
     }
 
     /// Retrieves the data from the storage based on hashes of the data [`segments`][Segment],
     /// or Error(NotFound) if some of the hashes were not present in the base.
     pub fn retrieve(&self, request: &[H::Hash]) -> io::Result<Vec<Vec<u8>>> {
+        self.retrieve_measured(request, &mut ReadMeasurements::default())
+    }
+
+    /// Retrieves the data like [`retrieve`][ChunkStorage::retrieve],
+    /// adding time spent on database and target map accesses and on decompression to `measurements`.
+    pub fn retrieve_measured(
+        &self,
+        request: &[H::Hash],
+        measurements: &mut ReadMeasurements,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let start = Instant::now();
         let retrieved = self.database.get_multi(request)?;
+        measurements.retrieve_time += start.elapsed();
 
+        // only chunks left in the database are compressed, the target map keeps the data decompressed
         retrieved
             .into_iter()
-            .map(|container| match &container.0 {
-                Data::Chunk(chunk) => Ok(chunk.clone()),
-                Data::TargetChunk(keys) => Ok(self
-                    .target_map
-                    .get_multi(&keys)?
-                    .into_iter()
-                    .flatten()
-                    .collect()),
+            .map(|container| match container.0 {
+                Data::Chunk(chunk) => match &self.compressor {
+                    Some(compressor) => {
+                        let start = Instant::now();
+                        let chunk = compressor.decompress(&chunk);
+                        measurements.decompress_time += start.elapsed();
+                        chunk
+                    }
+                    None => Ok(chunk),
+                },
+                Data::TargetChunk(keys) => {
+                    let start = Instant::now();
+                    let chunk = self.target_map.get_multi(&keys)?.concat();
+                    measurements.reconstruct_time += start.elapsed();
+                    Ok(chunk)
+                }
+                Data::DeltaChunk { base, delta } => {
+                    let start = Instant::now();
+                    let chunk = apply_delta(
+                        &self.target_map.get_multi(&base)?.concat(),
+                        &self.target_map.get_multi(&delta)?.concat(),
+                    );
+                    measurements.reconstruct_time += start.elapsed();
+                    chunk
+                }
             })
             .collect()
     }
@@ -120,7 +637,6 @@ where
 /// Writer that conducts operations on [Storage].
 /// Only exists during [FileSystem::write_to_file][crate::FileSystem::write_to_file].
 /// Receives `buffer` from [FileHandle][crate::file_layer::FileHandle] and gives it back after a successful write.
-#[derive(Debug)]
 struct StorageWriter<'handle, C, H>
 where
     C: Chunker,
@@ -128,6 +644,8 @@ where
 {
     chunker: &'handle mut C,
     hasher: &'handle mut H,
+    parallel_hasher: Option<&'handle mut Box<dyn HashChunks<H::Hash> + Send>>,
+    compressor: Option<&'handle (dyn Compressor + Send)>,
 }
 
 impl<'handle, C, H> StorageWriter<'handle, C, H>
@@ -136,59 +654,48 @@ where
     H: Hasher,
 {
     fn new(chunker: &'handle mut C, hasher: &'handle mut H) -> Self {
-        Self { chunker, hasher }
+        Self {
+            chunker,
+            hasher,
+            parallel_hasher: None,
+            compressor: None,
+        }
     }
 
-    /// Writes 1 MB of data to the [`base`][crate::base::Base] storage after deduplication.
+    /// Writes a segment of data to the [`base`][crate::base::Base] storage after deduplication.
     ///
     /// Returns resulting lengths of [chunks][crate::chunker::Chunk] with corresponding hash,
     /// along with amount of time spent on chunking and hashing.
     fn write<K, B: Database<H::Hash, DataContainer<K>>>(
         &mut self,
         data: &[u8],
+        continued: bool,
         base: &mut B,
     ) -> io::Result<SpansInfo<H::Hash>> {
-        //debug_assert!(data.len() == SEG_SIZE); // we assume that all given data segments are 1MB long for now
-
-        let mut buffer = self.chunker.remainder().to_vec();
-        buffer.extend_from_slice(data);
-
-        let empty = Vec::with_capacity(self.chunker.estimate_chunk_count(&buffer));
-
-        let start = Instant::now();
-        let chunks = self.chunker.chunk_data(&buffer, empty);
-        let chunk_time = start.elapsed();
+        let (buffer, chunks, mut measurements) = chunk_segment(self.chunker, data, continued);
 
         let start = Instant::now();
-        let hashes = chunks
-            .iter()
-            .map(|chunk| self.hasher.hash(&buffer[chunk.range()]))
-            .collect::<Vec<_>>();
-        let hash_time = start.elapsed();
-
-        let chunks = chunks
-            .iter()
-            .map(|chunk| buffer[chunk.range()].to_vec())
-            .collect::<Vec<_>>();
-
-        // have to copy hashes? or do something else?
-        let spans = hashes
-            .iter()
-            .zip(chunks.iter())
-            .map(|(hash, chunk)| Span::new(hash.clone(), chunk.len()))
-            .collect();
-
-        let converted_chunks = chunks
-            .into_iter()
-            .map(|chunk| DataContainer(Data::Chunk(chunk)));
-
-        let pairs = hashes.into_iter().zip(converted_chunks).collect(); // we allocate memory for (K, V) pairs, which is not really required
-        base.insert_multi(pairs)?;
+        let (hashes, hash_cpu_time) = match &mut self.parallel_hasher {
+            Some(parallel_hasher) => parallel_hasher.hash_chunks(&buffer, &chunks),
+            None => {
+                let hashes = chunks
+                    .iter()
+                    .map(|chunk| self.hasher.hash(&buffer[chunk.range()]))
+                    .collect::<Vec<_>>();
+                (hashes, start.elapsed())
+            }
+        };
+        measurements.hash_time = start.elapsed();
+        measurements.hash_cpu_time = hash_cpu_time;
 
-        Ok(SpansInfo {
-            spans,
-            measurements: WriteMeasurements::new(chunk_time, hash_time),
-        })
+        store_chunks(
+            &buffer,
+            &chunks,
+            hashes,
+            measurements,
+            self.compressor,
+            base,
+        )
     }
 
     /// Flushes remaining data to the storage and returns its [`span`][Span] with hashing and chunking times.
@@ -204,28 +711,170 @@ where
             });
         }
 
-        let remainder = self.chunker.remainder().to_vec();
-        let remainder_length = remainder.len();
+        let remainder = self.chunker.remainder();
         let start = Instant::now();
-        let hash = self.hasher.hash(&remainder);
+        let hash = self.hasher.hash(remainder);
         let hash_time = start.elapsed();
 
-        base.insert(hash.clone(), DataContainer(Data::Chunk(remainder)))?;
+        let mut measurements = WriteMeasurements::new(Duration::default(), hash_time);
+        measurements.chunk_count = 1;
+        let chunks = [Chunk::new(0, remainder.len())];
+        store_chunks(
+            remainder,
+            &chunks,
+            vec![hash],
+            measurements,
+            self.compressor,
+            base,
+        )
+    }
+}
 
-        let span = Span::new(hash, remainder_length);
-        Ok(SpansInfo {
-            spans: vec![span],
-            measurements: WriteMeasurements::new(Duration::default(), hash_time),
-        })
+/// Scrubs a single chunk stored in the `container` and returns `true`, if the scrubber moved it to the target map.
+///
+/// If chunks are compressed with the `compressor`, the scrubber gets the decompressed chunk,
+/// and the compressed chunk is only replaced if the scrubbed one was moved to the target map.
+fn scrub_container<Hash, B, K>(
+    scrubber: &mut (dyn Scrub<Hash, B, K> + Send),
+    target_map: &mut Box<dyn Database<K, Vec<u8>> + Send>,
+    compressor: Option<&(dyn Compressor + Send)>,
+    container: &mut DataContainer<K>,
+    measurements: &mut ScrubMeasurements,
+) -> io::Result<bool>
+where
+    Hash: ChunkHash,
+    B: Database<Hash, DataContainer<K>>,
+    for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
+{
+    let Data::Chunk(chunk) = container.extract() else {
+        return Ok(false);
+    };
+
+    let Some(compressor) = compressor else {
+        scrubber.scrub_chunk(container, target_map, measurements)?;
+        return Ok(!matches!(container.extract(), Data::Chunk(_)));
+    };
+
+    let mut scrubbed = DataContainer::from(compressor.decompress(chunk)?);
+    scrubber.scrub_chunk(&mut scrubbed, target_map, measurements)?;
+
+    let moved = !matches!(scrubbed.extract(), Data::Chunk(_));
+    if moved {
+        *container = scrubbed;
+    }
+    Ok(moved)
+}
+
+/// Increases reference counts of the target map keys the container refers to.
+fn acquire_targets<K: ChunkHash>(counts: &mut HashMap<K, usize>, container: &DataContainer<K>) {
+    for key in container.target_keys() {
+        *counts.entry(key.clone()).or_insert(0) += 1;
     }
 }
 
+/// Assembles the buffer from the chunker's remainder, if the data is `continued`, and the `data`, and chunks it.
+/// If there is no remainder, the data itself is chunked without copying it.
+///
+/// Returns the buffer and its chunks, along with time spent on assembling the buffer and chunking.
+pub(crate) fn chunk_segment<'a, C: Chunker>(
+    chunker: &mut C,
+    data: &'a [u8],
+    continued: bool,
+) -> (Cow<'a, [u8]>, Vec<Chunk>, WriteMeasurements) {
+    let start = Instant::now();
+    let buffer = match continued && !chunker.remainder().is_empty() {
+        true => Cow::Owned([chunker.remainder(), data].concat()),
+        false => Cow::Borrowed(data),
+    };
+    let buffer_time = start.elapsed();
+
+    let empty = Vec::with_capacity(chunker.estimate_chunk_count(&buffer));
+
+    let start = Instant::now();
+    let chunks = chunker.chunk_data(&buffer, empty);
+
+    let mut measurements = WriteMeasurements::new(start.elapsed(), Duration::default());
+    measurements.buffer_time = buffer_time;
+    measurements.chunk_count = chunks.len();
+    (buffer, chunks, measurements)
+}
+
+/// Inserts the hashed `chunks` of the `buffer` into the database, skipping the ones that are already stored,
+/// and returns their spans. New chunks are compressed with the `compressor`, if it is given.
+pub(crate) fn store_chunks<Hash: ChunkHash, K, B: Database<Hash, DataContainer<K>>>(
+    buffer: &[u8],
+    chunks: &[Chunk],
+    hashes: Vec<Hash>,
+    mut measurements: WriteMeasurements,
+    compressor: Option<&(dyn Compressor + Send)>,
+    base: &mut B,
+) -> io::Result<SpansInfo<Hash>> {
+    // chunks that are already stored are not copied and inserted again,
+    // so that the stored ones, possibly processed by the scrubber, are not overwritten
+    let start = Instant::now();
+    let mut new_hashes = HashSet::new();
+    let mut new_chunks = vec![];
+    let mut spans = Vec::with_capacity(chunks.len());
+    for (chunk, hash) in chunks.iter().zip(hashes) {
+        measurements.written_bytes += chunk.length();
+        if new_hashes.contains(&hash) || base.contains(&hash) {
+            measurements.duplicate_bytes += chunk.length();
+        } else {
+            measurements.new_bytes += chunk.length();
+            new_hashes.insert(hash.clone());
+            new_chunks.push((hash.clone(), chunk));
+        }
+        spans.push(Span::new(hash, chunk.length()));
+    }
+    let lookup_time = start.elapsed();
+
+    if let Some(compressor) = compressor {
+        let start = Instant::now();
+        let pairs = new_chunks
+            .into_iter()
+            .map(|(hash, chunk)| {
+                let compressed = compressor.compress(&buffer[chunk.range()]);
+                measurements.compressed_bytes += compressed.len();
+                (hash, DataContainer::from(compressed))
+            })
+            .collect();
+        measurements.compress_time = start.elapsed();
+
+        let start = Instant::now();
+        base.insert_multi(pairs)?;
+        measurements.insert_time = lookup_time + start.elapsed();
+    } else {
+        // chunks are borrowed from the buffer, the database copies them only if it needs to
+        let start = Instant::now();
+        let pairs = new_chunks
+            .into_iter()
+            .map(|(hash, chunk)| (hash, &buffer[chunk.range()]))
+            .collect();
+        measurements.copy_time = start.elapsed();
+
+        let start = Instant::now();
+        base.insert_multi_borrowed(pairs)?;
+        measurements.insert_time = lookup_time + start.elapsed();
+        measurements.compressed_bytes = measurements.new_bytes;
+    }
+
+    Ok(SpansInfo {
+        spans,
+        measurements,
+    })
+}
+
 impl<K> DataContainer<K> {
     /// Replaces stored data with the vector of target map keys, using which the chunk can be restored.
     pub fn make_target(&mut self, keys: Vec<K>) {
         self.0 = Data::TargetChunk(keys);
     }
 
+    /// Replaces stored data with the target map keys of the base and of the delta, using which the chunk can be restored.
+    pub fn make_delta(&mut self, base: Vec<K>, delta: Vec<K>) {
+        self.0 = Data::DeltaChunk { base, delta };
+    }
+
     /// Gets the reference to the data stored in the container.
     pub fn extract(&self) -> &Data<K> {
         &self.0
@@ -235,6 +884,16 @@ impl<K> DataContainer<K> {
     pub fn extract_mut(&mut self) -> &mut Data<K> {
         &mut self.0
     }
+
+    /// Returns the target map keys that the container refers to, none if it stores the chunk itself.
+    pub(crate) fn target_keys(&self) -> impl Iterator<Item = &K> {
+        let (first, second): (&[K], &[K]) = match &self.0 {
+            Data::Chunk(_) => (&[], &[]),
+            Data::TargetChunk(keys) => (keys, &[]),
+            Data::DeltaChunk { base, delta } => (base, delta),
+        };
+        first.iter().chain(second)
+    }
 }
 
 impl<K> From<Vec<u8>> for DataContainer<K> {
@@ -243,11 +902,23 @@ impl<K> From<Vec<u8>> for DataContainer<K> {
     }
 }
 
+impl<K> From<&[u8]> for DataContainer<K> {
+    fn from(value: &[u8]) -> Self {
+        Self(Data::Chunk(value.to_vec()))
+    }
+}
+
 impl<K> std::fmt::Debug for Data<K> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Data::Chunk(chunk) => write!(f, "Chunk with len {}", chunk.len()),
             Data::TargetChunk(keys) => write!(f, "TargetChunk with {} keys", keys.len()),
+            Data::DeltaChunk { base, delta } => write!(
+                f,
+                "DeltaChunk with {} base keys and {} delta keys",
+                base.len(),
+                delta.len()
+            ),
         }
     }
 }
@@ -261,12 +932,17 @@ impl<K> Default for Data<K> {
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::sync::Arc;
 
+    use crate::chunkers::FSChunker;
     use crate::hashers::SimpleHasher;
+    use crate::map::Database;
     use crate::scrub::DumbScrubber;
     use crate::storage::ChunkStorage;
     use crate::storage::DataContainer;
     use crate::storage::ScrubMeasurements;
+    use crate::storage::WriteActivity;
+    use crate::Data;
 
     #[test]
     fn hashmap_works_as_cdc_map() {
@@ -278,6 +954,14 @@ mod tests {
             scrubber: Box::new(DumbScrubber),
             target_map: Box::new(HashMap::default()),
             hasher: SimpleHasher,
+            parallel_hasher: None,
+            hash_threads: 1,
+            compressor: None,
+            ref_counts: HashMap::default(),
+            target_ref_counts: HashMap::default(),
+            pending: Default::default(),
+            pending_lengths: HashMap::default(),
+            activity: Arc::new(WriteActivity::new()),
         };
 
         let measurements = chunk_storage
@@ -288,4 +972,62 @@ mod tests {
 
         println!("{:?}", chunk_storage.database)
     }
+
+    #[test]
+    fn chunk_is_removed_when_no_longer_referenced() {
+        let map: HashMap<Vec<u8>, DataContainer<i32>> = HashMap::new();
+        let mut chunk_storage = ChunkStorage::new(
+            map,
+            Box::new(HashMap::default()),
+            Box::new(DumbScrubber),
+            SimpleHasher,
+        );
+        let mut chunker = FSChunker::new(4);
+
+        let first = chunk_storage.write(&[1; 8], &mut chunker, false).unwrap();
+        let second = chunk_storage.write(&[1; 4], &mut chunker, true).unwrap();
+        assert_eq!(first.spans.len() + second.spans.len(), 3);
+
+        let hash = vec![1; 4];
+        assert_eq!(chunk_storage.ref_counts.get(&hash), Some(&3));
+
+        chunk_storage.release(&[hash.clone(), hash.clone()]);
+        assert_eq!(chunk_storage.ref_counts.get(&hash), Some(&1));
+        assert!(chunk_storage.database.contains(&hash));
+
+        chunk_storage.release(std::slice::from_ref(&hash));
+        assert_eq!(chunk_storage.ref_counts.get(&hash), None);
+        assert!(!chunk_storage.database.contains(&hash));
+    }
+
+    #[test]
+    fn duplicate_chunks_are_not_overwritten() {
+        let map: HashMap<Vec<u8>, DataContainer<i32>> = HashMap::new();
+        let mut chunk_storage = ChunkStorage::new(
+            map,
+            Box::new(HashMap::default()),
+            Box::new(DumbScrubber),
+            SimpleHasher,
+        );
+        let mut chunker = FSChunker::new(4);
+
+        let first = chunk_storage.write(&[1; 10], &mut chunker, false).unwrap();
+        assert_eq!(first.measurements.new_bytes(), 4);
+        assert_eq!(first.measurements.duplicate_bytes(), 4);
+
+        let hash = vec![1; 4];
+        let container = chunk_storage.database.get_mut(&hash).unwrap();
+        container.make_target(vec![0]);
+
+        let second = chunk_storage.write(&[1; 8], &mut chunker, true).unwrap();
+        assert_eq!(second.measurements.new_bytes(), 0);
+        assert_eq!(second.measurements.duplicate_bytes(), 8);
+        assert_eq!(second.measurements.written_bytes(), 8);
+        assert_eq!(second.measurements.chunk_count(), 2);
+        let flushed = chunk_storage.flush(&mut chunker).unwrap();
+        assert_eq!(flushed.measurements.new_bytes(), 2);
+
+        let container = chunk_storage.database.get(&hash).unwrap();
+        assert!(matches!(container.extract(), Data::TargetChunk(keys) if keys == &[0]));
+    }
 }
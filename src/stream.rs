@@ -0,0 +1,209 @@
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::file_layer::FileHandle;
+use crate::map::Database;
+use crate::storage::DataContainer;
+use crate::system::FileSystem;
+use crate::{ChunkHash, Chunker, Hasher};
+
+/// Adapter that implements [`Read`], [`Write`] and [`Seek`] for an open file,
+/// borrowing the [`FileSystem`] and the [`FileHandle`] for its lifetime.
+///
+/// Reads and writes are buffered in [segments][FileSystem::segment_size], so that files can be streamed,
+/// e.g. with [`io::copy`], without holding their whole contents in memory.
+///
+/// Data written at the end of the file is appended to it, data written before the end overwrites
+/// the file's contents using [`write_at`][FileSystem::write_at]. Writing past the end of the file
+/// returns `ErrorKind::InvalidInput`. Buffered data is written when the stream is [flushed][Write::flush]
+/// or dropped. The handle must still be [closed][FileSystem::close_file] to store the end of the file
+/// held by the chunker and to record the changes in the journal.
+///
+/// The stream keeps its own position, which starts at the beginning of the file, and doesn't move the handle.
+pub struct FileStream<'a, B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    for<'b> &'b mut B: IntoIterator<Item = (&'b Hash, &'b mut DataContainer<K>)>,
+    C: Chunker,
+{
+    fs: &'a mut FileSystem<B, H, Hash, K>,
+    handle: &'a mut FileHandle<C>,
+    segment_size: usize,
+    position: usize,
+    /// Data read from the file, starting at `read_offset`.
+    read_buffer: Vec<u8>,
+    read_offset: usize,
+    /// Data that is not written to the file yet, starting at `write_offset`.
+    write_buffer: Vec<u8>,
+    write_offset: usize,
+}
+
+impl<'a, B, H, Hash, K, C> FileStream<'a, B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    for<'b> &'b mut B: IntoIterator<Item = (&'b Hash, &'b mut DataContainer<K>)>,
+    C: Chunker,
+{
+    /// Creates a stream over the file opened with the `handle`, positioned at the beginning of the file.
+    pub fn new(fs: &'a mut FileSystem<B, H, Hash, K>, handle: &'a mut FileHandle<C>) -> Self {
+        let segment_size = fs.segment_size();
+        Self {
+            fs,
+            handle,
+            segment_size,
+            position: 0,
+            read_buffer: vec![],
+            read_offset: 0,
+            write_buffer: Vec::with_capacity(segment_size),
+            write_offset: 0,
+        }
+    }
+
+    /// Returns current position of the stream in the file.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns size of the file, including the data that is written to it, but is not stored yet.
+    fn len(&self) -> io::Result<usize> {
+        let mut len = self.fs.file_size(self.handle.name())?;
+        if self.handle.remainder_pending {
+            len += self.handle.chunker.remainder().len();
+        }
+        if !self.write_buffer.is_empty() {
+            len = len.max(self.write_offset + self.write_buffer.len());
+        }
+        Ok(len)
+    }
+
+    /// Writes the buffered data to the file.
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        if self.write_buffer.is_empty() {
+            return Ok(());
+        }
+        self.read_buffer.clear();
+
+        let mut end = self.fs.file_size(self.handle.name())?;
+        if self.handle.remainder_pending {
+            end += self.handle.chunker.remainder().len();
+        }
+
+        if self.write_offset == end {
+            self.fs.write_to_file(self.handle, &self.write_buffer)?;
+        } else {
+            self.fs
+                .write_at(self.handle, self.write_offset, &self.write_buffer)?;
+        }
+        self.write_buffer.clear();
+        Ok(())
+    }
+}
+
+impl<B, H, Hash, K, C> Read for FileStream<'_, B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    for<'b> &'b mut B: IntoIterator<Item = (&'b Hash, &'b mut DataContainer<K>)>,
+    C: Chunker,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // written data may overlap the read buffer, flushing it drops the read buffer as well
+        self.flush_buffer()?;
+        let buffered = self.read_offset..self.read_offset + self.read_buffer.len();
+        if !buffered.contains(&self.position) {
+            // the end of the file, held by the chunker, can't be read until it is stored
+            if self.handle.remainder_pending {
+                self.fs.flush_remainder(self.handle)?;
+            }
+            self.read_buffer = self
+                .fs
+                .read_at(self.handle, self.position, self.segment_size)?;
+            self.read_offset = self.position;
+        }
+
+        let available = &self.read_buffer[self.position - self.read_offset..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl<B, H, Hash, K, C> Write for FileStream<'_, B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    for<'b> &'b mut B: IntoIterator<Item = (&'b Hash, &'b mut DataContainer<K>)>,
+    C: Chunker,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.position != self.write_offset + self.write_buffer.len() {
+            self.flush_buffer()?;
+        }
+        if self.write_buffer.is_empty() {
+            if self.position > self.len()? {
+                return Err(ErrorKind::InvalidInput.into());
+            }
+            self.write_offset = self.position;
+        }
+
+        let len = buf.len().min(self.segment_size - self.write_buffer.len());
+        self.write_buffer.extend_from_slice(&buf[..len]);
+        self.position += len;
+        if self.write_buffer.len() == self.segment_size {
+            self.flush_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buffer()
+    }
+}
+
+impl<B, H, Hash, K, C> Seek for FileStream<'_, B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    for<'b> &'b mut B: IntoIterator<Item = (&'b Hash, &'b mut DataContainer<K>)>,
+    C: Chunker,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(offset) => (self.len()?, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        self.position = base
+            .checked_add_signed(offset as isize)
+            .ok_or(ErrorKind::InvalidInput)?;
+        Ok(self.position as u64)
+    }
+}
+
+impl<B, H, Hash, K, C> Drop for FileStream<'_, B, H, Hash, K, C>
+where
+    B: Database<Hash, DataContainer<K>>,
+    H: Hasher<Hash = Hash>,
+    Hash: ChunkHash,
+    K: ChunkHash,
+    for<'b> &'b mut B: IntoIterator<Item = (&'b Hash, &'b mut DataContainer<K>)>,
+    C: Chunker,
+{
+    fn drop(&mut self) {
+        // errors can't be reported here, flush the stream explicitly to handle them
+        let _ = self.flush_buffer();
+    }
+}
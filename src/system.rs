@@ -1,14 +1,22 @@
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::file_layer::{FileHandle, FileLayer};
+use crate::file_layer::{normalize, FileHandle, FileLayer, OpenMode};
+use crate::journal::{self, Journal, Record};
 use crate::map::Database;
-use crate::scrub::{DumbScrubber, Scrub, ScrubMeasurements};
-use crate::storage::{ChunkStorage, DataContainer};
-use crate::WriteMeasurements;
-use crate::{ChunkHash, SEG_SIZE};
-use crate::{Chunker, Hasher};
+use crate::scrub::{DumbScrubber, Scrub, ScrubBudget, ScrubMeasurements};
+use crate::stats::FileSystemStats;
+use crate::storage::{ChunkStorage, DataContainer, Span, SpansInfo, WriteActivity};
+use crate::{Chunk, Chunker, Compressor, Hasher};
+use crate::{ChunkHash, Encode, SEG_SIZE};
+use crate::{ReadMeasurements, WriteMeasurements};
 
 /// A file system provided by chunkfs.
 pub struct FileSystem<B, H, Hash, K>
@@ -20,6 +28,16 @@ where
 {
     storage: ChunkStorage<H, Hash, B, K>,
     file_layer: FileLayer<Hash>,
+    /// Journal of the operations made after the metadata was last saved, if it was.
+    journal: Option<Journal<Hash>>,
+    /// Spans replaced through open handles, keyed by the handle ids,
+    /// which are released once the changes are recorded in the journal.
+    released: HashMap<u64, ReleasedSpans<Hash>>,
+    /// Id of the next opened handle.
+    next_handle: u64,
+    read_measurements: ReadMeasurements,
+    /// Size of the segments in which data is given to the chunker and read from the files.
+    segment_size: usize,
 }
 
 impl<B, H, Hash> FileSystem<B, H, Hash, i32>
@@ -30,15 +48,31 @@ where
     for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<i32>)>,
 {
     pub fn new_cdc_only(base: B, hasher: H) -> Self {
-        Self {
-            storage: ChunkStorage::new(
-                base,
-                Box::<HashMap<i32, Vec<u8>>>::default(),
-                Box::new(DumbScrubber),
-                hasher,
-            ),
-            file_layer: Default::default(),
-        }
+        Self::new(
+            base,
+            Box::<HashMap<i32, Vec<u8>>>::default(),
+            Box::new(DumbScrubber),
+            hasher,
+        )
+    }
+
+    /// Restores a file system, created by [`new_cdc_only`][FileSystem::new_cdc_only],
+    /// from the saved metadata. See [`open_existing`][FileSystem::open_existing].
+    pub fn open_existing_cdc_only(
+        base: B,
+        hasher: H,
+        metadata: impl AsRef<Path>,
+    ) -> io::Result<Self>
+    where
+        Hash: Encode,
+    {
+        Self::open_existing(
+            base,
+            Box::<HashMap<i32, Vec<u8>>>::default(),
+            Box::new(DumbScrubber),
+            hasher,
+            metadata,
+        )
     }
 }
 
@@ -47,98 +81,866 @@ where
     B: Database<Hash, DataContainer<K>>,
     H: Hasher<Hash = Hash>,
     Hash: ChunkHash,
+    K: ChunkHash,
     for<'a> &'a mut B: IntoIterator<Item = (&'a Hash, &'a mut DataContainer<K>)>,
 {
     /// Creates a file system with the given [`base`][Base].
     pub fn new(
         database: B,
-        target_map: Box<dyn Database<K, Vec<u8>>>,
-        scrubber: Box<dyn Scrub<Hash, B, K>>,
+        target_map: Box<dyn Database<K, Vec<u8>> + Send>,
+        scrubber: Box<dyn Scrub<Hash, B, K> + Send>,
         hasher: H,
     ) -> Self {
         Self {
             storage: ChunkStorage::new(database, target_map, scrubber, hasher),
             file_layer: Default::default(),
+            journal: None,
+            released: HashMap::new(),
+            next_handle: 0,
+            read_measurements: ReadMeasurements::default(),
+            segment_size: SEG_SIZE,
         }
     }
 
+    /// Restores a file system from the metadata saved by [`save_metadata`][FileSystem::save_metadata]
+    /// and the `database` that contains the chunks of its files.
+    ///
+    /// Operations recorded in the journal after the metadata was saved are replayed,
+    /// and chunks that are not referenced by any file, e.g. written by an interrupted operation, are removed.
+    /// An interrupted scrub is started again, and chunks that were not scrubbed incrementally yet stay pending.
+    /// The file system keeps recording operations in the same journal.
+    ///
+    /// If the file system was scrubbed, the `target_map` must contain the data the scrubber moved to it,
+    /// so it has to be persistent, e.g. a [`DiskTargetMap`][crate::DiskTargetMap].
+    ///
+    /// Returns `ErrorKind::InvalidData`, if the metadata or the journal is corrupted
+    /// or refers to chunks missing from the database, or if scrubbed chunks refer to data missing from the target map,
+    /// and `ErrorKind::Unsupported`, if the metadata was saved in a different format version.
+    pub fn open_existing(
+        database: B,
+        target_map: Box<dyn Database<K, Vec<u8>> + Send>,
+        scrubber: Box<dyn Scrub<Hash, B, K> + Send>,
+        hasher: H,
+        metadata: impl AsRef<Path>,
+    ) -> io::Result<Self>
+    where
+        Hash: Encode,
+    {
+        let journal_path = journal::journal_path(metadata.as_ref());
+        let metadata = fs::read(metadata)?;
+        let mut system = Self {
+            file_layer: FileLayer::from_bytes(&metadata)?,
+            ..Self::new(database, target_map, scrubber, hasher)
+        };
+
+        let (records, len) = match Journal::read(&journal_path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => (vec![], 0),
+            result => result?,
+        };
+        // the journal belongs to older metadata, if saving the metadata was interrupted before the journal was reset
+        let (scrub_interrupted, pending) = match records.first() {
+            Some(Record::Checkpoint(checksum)) if *checksum == journal::checksum(&metadata) => {
+                let replayed = system.replay(records)?;
+                system.journal = Some(Journal::open(&journal_path, len)?);
+                replayed
+            }
+            _ => {
+                system.journal = Some(Journal::create(
+                    &journal_path,
+                    journal::checksum(&metadata),
+                )?);
+                (false, vec![])
+            }
+        };
+
+        let hashes = system.file_layer.span_lengths().map(|(hash, _)| hash);
+        system.storage.restore(hashes)?;
+        system.storage.collect_garbage()?;
+        system.storage.restore_pending(pending)?;
+        if scrub_interrupted {
+            system.scrub()?;
+        }
+        Ok(system)
+    }
+
+    /// Applies the records of the journal to the file layer.
+    ///
+    /// Returns `true`, if the journal ends with a scrub that was not finished,
+    /// along with the chunks that were pending for an incremental scrub, oldest first.
+    /// Chunks written after the metadata was saved are pending, unless they were referenced before or scrubbed since.
+    fn replay(&mut self, records: Vec<Record<Hash>>) -> io::Result<(bool, Vec<Span<Hash>>)> {
+        let mut scrub_interrupted = false;
+        let mut pending = vec![];
+        let mut known: HashSet<Hash> = self
+            .file_layer
+            .span_lengths()
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for record in records.into_iter().skip(1) {
+            match record {
+                Record::ScrubStarted => scrub_interrupted = true,
+                Record::ScrubFinished => {
+                    scrub_interrupted = false;
+                    pending.clear();
+                }
+                Record::Pending(spans) => {
+                    known.extend(spans.iter().map(|span| span.hash.clone()));
+                    pending.extend(spans);
+                }
+                Record::Scrubbed(hashes) => {
+                    let scrubbed: HashSet<Hash> = hashes.into_iter().collect();
+                    pending.retain(|span: &Span<Hash>| !scrubbed.contains(&span.hash));
+                }
+                record => {
+                    let written = self
+                        .apply(record)
+                        .map_err(|_| io::Error::from(ErrorKind::InvalidData))?;
+                    pending.extend(
+                        written
+                            .into_iter()
+                            .filter(|span| known.insert(span.hash.clone())),
+                    );
+                }
+            }
+        }
+        Ok((scrub_interrupted, pending))
+    }
+
+    /// Applies a single record of the journal to the file layer. Reference counts of the chunks are not changed.
+    ///
+    /// Returns the spans written to the file layer by the record.
+    fn apply(&mut self, record: Record<Hash>) -> io::Result<Vec<Span<Hash>>> {
+        match record {
+            Record::Create(name) => self.file_layer.insert(&name, true).map(|_| vec![]),
+            Record::Delete(name) => self.file_layer.delete(&name).map(|_| vec![]),
+            Record::Rename(old, new) => self.file_layer.rename(&old, new).map(|_| vec![]),
+            Record::Mkdir(path) => self.file_layer.mkdir(&path).map(|_| vec![]),
+            Record::RemoveDir(path) => self.file_layer.remove_dir(&path).map(|_| vec![]),
+            Record::Splice { name, range, spans } => self
+                .file_layer
+                .splice_by_name(&name, range, spans.clone())
+                .map(|_| spans),
+            Record::Truncate { name, len, cut } => {
+                let mut written = vec![];
+                self.file_layer.truncate(&name, len, |_, kept| {
+                    let span = Span::new(cut.ok_or(ErrorKind::InvalidData)?, kept);
+                    written.push(span.clone());
+                    Ok(span)
+                })?;
+                Ok(written)
+            }
+            Record::Checkpoint(_)
+            | Record::ScrubStarted
+            | Record::ScrubFinished
+            | Record::Pending(_)
+            | Record::Scrubbed(_) => Err(ErrorKind::InvalidData.into()),
+        }
+    }
+
+    /// Saves names, sizes and span hashes of all files and directories to the file at `path`,
+    /// and flushes the database, so that the file system can be restored by [`open_existing`][FileSystem::open_existing].
+    ///
+    /// Afterwards, all operations that change the file system are recorded in a journal,
+    /// located next to the metadata file, until the metadata is saved again.
+    /// Data written to a file becomes part of the journal once the file is closed.
+    ///
+    /// The metadata file is replaced atomically. Data that is still buffered in open handles is not saved,
+    /// so the handles should be closed first.
+    pub fn save_metadata(&mut self, path: impl AsRef<Path>) -> io::Result<()>
+    where
+        Hash: Encode,
+    {
+        self.storage.sync()?;
+
+        let path = path.as_ref();
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        let metadata = self.file_layer.to_bytes();
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(&metadata)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+
+        let mut journal =
+            Journal::create(&journal::journal_path(path), journal::checksum(&metadata))?;
+        let pending = self.storage.pending();
+        if !pending.is_empty() {
+            journal.append(&Record::Pending(pending))?;
+        }
+        self.journal = Some(journal);
+        Ok(())
+    }
+
+    /// Records the operation in the journal, if there is one.
+    ///
+    /// The database is flushed first, so that a record never refers to chunks missing from the database.
+    fn commit(&mut self, record: Record<Hash>) -> io::Result<()> {
+        let Some(journal) = &mut self.journal else {
+            return Ok(());
+        };
+
+        self.storage.sync()?;
+        journal.append(&record)
+    }
+
+    /// Returns size of the segments in which written data is given to the chunker,
+    /// and which are read by [`read_from_file`][FileSystem::read_from_file]. Defaults to 1 MB.
+    pub fn segment_size(&self) -> usize {
+        self.segment_size
+    }
+
+    /// Sets size of the segments in which written data is given to the chunker,
+    /// and which are read by [`read_from_file`][FileSystem::read_from_file].
+    ///
+    /// Segments may be smaller than the chunks, in which case the chunker accumulates data of several segments.
+    ///
+    /// # Panics
+    /// Panics if `size` is zero.
+    pub fn set_segment_size(&mut self, size: usize) {
+        assert!(size > 0, "segment size must be positive");
+        self.segment_size = size;
+    }
+
+    /// Makes written data be hashed on the given amount of `threads`, each with its own copy of the hasher.
+    /// Chunks of each segment are split between the threads, the order of the resulting spans stays the same.
+    ///
+    /// With `0` or `1` threads, chunks are hashed sequentially on the calling thread, which is the default.
+    pub fn set_hash_threads(&mut self, threads: usize)
+    where
+        H: Clone + Send + 'static,
+        Hash: Send + 'static,
+    {
+        self.storage.set_hash_threads(threads)
+    }
+
+    /// Makes the file system compress chunks with the given `compressor` before storing them,
+    /// and decompress them when they are read.
+    ///
+    /// Chunks are decompressed before they are [scrubbed][FileSystem::scrub], so the scrubber has to support
+    /// [scrubbing single chunks][Scrub::scrub_chunk].
+    ///
+    /// Should be set before any data is written. The compressor is not saved along with the file system,
+    /// so the same one must be set again after [reopening][FileSystem::open_existing] it.
+    pub fn set_compressor(&mut self, compressor: impl Compressor + Send + 'static) {
+        self.storage.set_compressor(compressor)
+    }
+
     /// Checks if the file with the given `name` exists.
     pub fn file_exists(&self, name: &str) -> bool {
         self.file_layer.file_exists(name)
     }
 
-    /// Tries to open a file with the given name and returns its `FileHandle` if it exists,
-    /// or `None`, if it doesn't.
-    pub fn open_file<C: Chunker>(&self, name: &str, chunker: C) -> io::Result<FileHandle<C>> {
-        self.file_layer.open(name, chunker)
+    /// Returns size of the file with the given `name` in bytes.
+    /// Data that is still buffered by an open [`FileHandle`] is not counted until the handle is closed.
+    pub fn file_size(&self, name: &str) -> io::Result<usize> {
+        self.file_layer.file_size(name)
+    }
+
+    /// Checks if the directory with the given `path` exists.
+    pub fn dir_exists(&self, path: &str) -> bool {
+        self.file_layer.dir_exists(path)
+    }
+
+    /// Creates an empty directory at the given `path`.
+    /// Returns `ErrorKind::NotFound`, if the parent directory doesn't exist,
+    /// or `ErrorKind::AlreadyExists`, if a file or directory with the same path exists.
+    pub fn mkdir(&mut self, path: &str) -> io::Result<()> {
+        self.file_layer.mkdir(path)?;
+        self.commit(Record::Mkdir(path.to_string()))
     }
 
-    /// Creates a file with the given name and returns its `FileHandle`.
-    /// Returns `ErrorKind::AlreadyExists`, if the file with the same name exists in the file system.
+    /// Returns names of all files and directories located in the directory at the given `path`.
+    pub fn list_dir(&self, path: &str) -> io::Result<Vec<String>> {
+        self.file_layer.list_dir(path)
+    }
+
+    /// Removes an empty directory at the given `path`.
+    /// Returns `ErrorKind::DirectoryNotEmpty`, if the directory contains any files or directories.
+    pub fn remove_dir(&mut self, path: &str) -> io::Result<()> {
+        self.file_layer.remove_dir(path)?;
+        self.commit(Record::RemoveDir(path.to_string()))
+    }
+
+    /// Tries to open a file at the given path in the given [`mode`][OpenMode] and returns its `FileHandle`
+    /// if it exists, or `ErrorKind::NotFound`, if it doesn't.
+    ///
+    /// In [`OpenMode::Append`], the handle starts at the end of the file. Before data is first appended through it,
+    /// the last span of the file is chunked again, so that the chunker continues from the last chunk boundary,
+    /// as if the file was never closed.
+    pub fn open_file<C: Chunker>(
+        &mut self,
+        name: &str,
+        chunker: C,
+        mode: OpenMode,
+    ) -> io::Result<FileHandle<C>> {
+        let mut handle = self.file_layer.open(name, chunker, mode)?;
+        handle.id = self.next_handle;
+        self.next_handle += 1;
+        match mode {
+            OpenMode::Read => {}
+            OpenMode::Append => {
+                handle.restore_pending = true;
+                handle.seek(self.file_layer.file_size(name)?);
+            }
+            OpenMode::Truncate => self.truncate(name, 0)?,
+        }
+        Ok(handle)
+    }
+
+    /// Moves the data of the last span of the file back to the chunker, if the handle was opened
+    /// in [`OpenMode::Append`] and nothing was appended through it yet.
+    pub(crate) fn restore_remainder<C: Chunker>(
+        &mut self,
+        handle: &mut FileHandle<C>,
+    ) -> io::Result<()> {
+        if !handle.restore_pending {
+            return Ok(());
+        }
+
+        let span_count = self.file_layer.span_count(handle)?;
+        handle.restore_pending = false;
+        if span_count == 0 {
+            return Ok(());
+        }
+
+        let last = span_count - 1;
+        let start = self.file_layer.span_offset(handle, last)?;
+        let size = self.file_layer.file_size(handle.name())?;
+        let (data, _) = self.read_range(handle, start, size - start)?;
+
+        let info = self.storage.write(&data, &mut handle.chunker, false)?;
+        handle.remainder_pending = true;
+        let start = *handle.uncommitted.get_or_insert(last);
+        let replaced = self.file_layer.splice(handle, last..span_count, info)?;
+        // the replaced span stays in the journaled state of the file until the handle is flushed
+        self.released
+            .entry(handle.id)
+            .or_insert_with(|| ReleasedSpans {
+                name: handle.name().to_string(),
+                start,
+                hashes: vec![],
+            })
+            .hashes
+            .extend(replaced);
+        Ok(())
+    }
+
+    /// Removes the spans replaced through the handles open for the file with the given `name`
+    /// from the ones waiting for the handles to be flushed, and returns them.
+    fn take_released(&mut self, name: &str) -> Vec<ReleasedSpans<Hash>> {
+        let ids: Vec<u64> = self
+            .released
+            .iter()
+            .filter(|(_, released)| released.name == normalize(name))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.iter()
+            .filter_map(|id| self.released.remove(id))
+            .collect()
+    }
+
+    /// Truncates the file with the given `name` to `len` bytes.
+    /// Chunks that are no longer referenced by any file are removed from the storage.
+    ///
+    /// Returns `ErrorKind::InvalidInput`, if `len` is larger than the file size.
+    /// Handles that are open for writing should be closed before truncating the file.
+    pub fn truncate(&mut self, name: &str, len: usize) -> io::Result<()> {
+        let storage = &mut self.storage;
+        let mut cut = None;
+        let removed = self.file_layer.truncate(name, len, |hash, kept| {
+            let mut data = storage.retrieve(std::slice::from_ref(hash))?.concat();
+            data.truncate(kept);
+            let span = storage.store(data)?;
+            cut = Some(span.hash.clone());
+            Ok(span)
+        })?;
+
+        self.commit(Record::Truncate {
+            name: name.to_string(),
+            len,
+            cut,
+        })?;
+        self.storage.release(&removed);
+        Ok(())
+    }
+
+    /// Creates a file at the given path and returns its `FileHandle`.
+    /// Returns `ErrorKind::AlreadyExists`, if the file with the same name exists in the file system,
+    /// or `ErrorKind::NotFound`, if its parent directory doesn't exist.
+    ///
+    /// If `create_new` is `true`, an existing file with the same name is replaced,
+    /// and its chunks are released.
     pub fn create_file<C: Chunker>(
         &mut self,
         name: String,
         chunker: C,
         create_new: bool,
     ) -> io::Result<FileHandle<C>> {
-        self.file_layer.create(name, chunker, create_new)
+        if create_new && self.file_exists(&name) {
+            self.delete_file(&name)?;
+        }
+        let mut handle = self.file_layer.create(name, chunker, create_new)?;
+        handle.id = self.next_handle;
+        self.next_handle += 1;
+        self.commit(Record::Create(handle.name().to_string()))?;
+        Ok(handle)
+    }
+
+    /// Deletes the file with the given name.
+    /// Chunks that are no longer referenced by any file are removed from the storage.
+    /// Returns `ErrorKind::NotFound`, if the file doesn't exist.
+    ///
+    /// Handles that are still open for the file return `ErrorKind::NotFound` afterwards.
+    pub fn delete_file(&mut self, name: &str) -> io::Result<()> {
+        let hashes = self.file_layer.delete(name)?;
+        self.commit(Record::Delete(name.to_string()))?;
+        self.storage.release(&hashes);
+        // the spans replaced through open handles are no longer part of the journaled file either
+        for released in self.take_released(name) {
+            self.storage.release(&released.hashes);
+        }
+        Ok(())
+    }
+
+    /// Renames the file named `old` to `new`.
+    /// Returns `ErrorKind::NotFound`, if the file doesn't exist,
+    /// or `ErrorKind::AlreadyExists`, if a file named `new` exists in the file system.
+    ///
+    /// Handles that are still open for `old` return `ErrorKind::NotFound` afterwards.
+    /// Handles that are open for writing should be closed before renaming the file,
+    /// otherwise the data they haven't flushed yet is lost.
+    pub fn rename_file(&mut self, old: &str, new: String) -> io::Result<()> {
+        self.file_layer.rename(old, new.clone())?;
+
+        // the handles can't record their changes after the rename, so the current spans of the file are recorded now
+        if normalize(old) != normalize(&new) {
+            let released = self.take_released(old);
+            if let Some(start) = released.iter().map(|released| released.start).min() {
+                self.commit(Record::Splice {
+                    name: old.to_string(),
+                    range: start..usize::MAX,
+                    spans: self.file_layer.spans_from(&new, start)?,
+                })?;
+                for released in released {
+                    self.storage.release(&released.hashes);
+                }
+            }
+        }
+        self.commit(Record::Rename(old.to_string(), new))
     }
 
-    /// Writes given data to the file.
+    /// Writes given data to the end of the file.
+    /// Returns `ErrorKind::PermissionDenied`, if the file was opened for reading.
     pub fn write_to_file<C: Chunker>(
         &mut self,
         handle: &mut FileHandle<C>,
         data: &[u8],
     ) -> io::Result<()> {
+        if handle.mode() == OpenMode::Read {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+
+        self.restore_remainder(handle)?;
+        handle
+            .uncommitted
+            .get_or_insert(self.file_layer.span_count(handle)?);
+
         let mut current = 0;
         let mut all_spans = vec![];
         while current < data.len() {
             let remaining = data.len() - current;
-            let to_process = min(SEG_SIZE, remaining);
+            let to_process = min(self.segment_size, remaining);
 
-            let spans = self
-                .storage
-                .write(&data[current..current + to_process], &mut handle.chunker)?;
+            let spans = self.storage.write(
+                &data[current..current + to_process],
+                &mut handle.chunker,
+                handle.remainder_pending,
+            )?;
+            handle.remainder_pending = true;
             all_spans.push(spans);
 
             current += to_process;
         }
 
         for spans in all_spans {
-            self.file_layer.write(handle, spans);
+            self.file_layer.write(handle, spans)?;
         }
 
         Ok(())
     }
 
+    /// Writes given data to the end of the file, like [`write_to_file`][FileSystem::write_to_file],
+    /// but chunks, hashes and stores different segments of the data at the same time.
+    ///
+    /// The chunker runs on a separate thread, segments are hashed by a pool of threads,
+    /// the amount of which is set by [`set_hash_threads`][FileSystem::set_hash_threads],
+    /// and are stored on the calling thread. The resulting spans are the same as with `write_to_file`.
+    /// Returns `ErrorKind::PermissionDenied`, if the file was opened for reading.
+    pub fn write_to_file_pipelined<C>(
+        &mut self,
+        handle: &mut FileHandle<C>,
+        data: &[u8],
+    ) -> io::Result<()>
+    where
+        C: Chunker + Send,
+        H: Clone + Send,
+        Hash: Send,
+    {
+        if handle.mode() == OpenMode::Read {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        self.restore_remainder(handle)?;
+        handle
+            .uncommitted
+            .get_or_insert(self.file_layer.span_count(handle)?);
+
+        let spans = self.storage.write_pipelined(
+            data,
+            self.segment_size,
+            &mut handle.chunker,
+            handle.remainder_pending,
+        )?;
+        handle.remainder_pending = true;
+        self.file_layer.write(handle, spans)?;
+        Ok(())
+    }
+
     /// Closes the file and ensures that all data that was written to it
     /// is stored. Returns [WriteMeasurements] containing chunking and hashing times.
+    ///
+    /// [`ReadMeasurements`] of the handle are added to the [total][FileSystem::read_measurements] of the file system.
     pub fn close_file<C: Chunker>(
         &mut self,
         mut handle: FileHandle<C>,
     ) -> io::Result<WriteMeasurements> {
-        let span = self.storage.flush(&mut handle.chunker)?;
-        self.file_layer.write(&mut handle, span);
-
+        self.flush_remainder(&mut handle)?;
+        self.read_measurements += handle.read_measurements();
         Ok(handle.close())
     }
 
+    /// Returns accumulated [`ReadMeasurements`] of all closed handles.
+    pub fn read_measurements(&self) -> ReadMeasurements {
+        self.read_measurements
+    }
+
+    /// Stores data that was written to the end of the file, but is still held by the chunker,
+    /// and records spans written through the handle in the journal.
+    pub(crate) fn flush_remainder<C: Chunker>(
+        &mut self,
+        handle: &mut FileHandle<C>,
+    ) -> io::Result<()> {
+        if handle.remainder_pending {
+            // the file is looked up first, so that the remainder isn't stored if the file no longer exists
+            self.file_layer.span_count(handle)?;
+            let span = self.storage.flush(&mut handle.chunker)?;
+            self.file_layer.write(handle, span)?;
+            handle.remainder_pending = false;
+        }
+
+        if let Some(start) = handle.uncommitted {
+            self.commit(Record::Splice {
+                name: handle.name().to_string(),
+                range: start..usize::MAX,
+                spans: self.file_layer.spans_from(handle.name(), start)?,
+            })?;
+            handle.uncommitted = None;
+
+            if let Some(released) = self.released.remove(&handle.id) {
+                self.storage.release(&released.hashes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to the file, starting at `offset`, overwriting its contents and extending the file if needed.
+    /// If the file is extended, the handle is moved to its new end, otherwise the handle's offset is not changed.
+    ///
+    /// Only the affected region is chunked again. It starts at the beginning of the span containing `offset`,
+    /// and ends at the first span boundary after the written data that is also found by the chunker,
+    /// because from that point chunking would produce the same spans as before. Replaced spans are released.
+    ///
+    /// The chunker must be the same as the one used to write the file, otherwise chunking never resynchronises,
+    /// and the whole rest of the file is chunked again.
+    /// Returns `ErrorKind::InvalidInput`, if `offset` is past the end of the file,
+    /// or `ErrorKind::PermissionDenied`, if the file was opened for reading.
+    pub fn write_at<C: Chunker>(
+        &mut self,
+        handle: &mut FileHandle<C>,
+        offset: usize,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if handle.mode() == OpenMode::Read {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        self.flush_remainder(handle)?;
+
+        let size = self.file_layer.file_size(handle.name())?;
+        if offset > size {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let end = offset + data.len();
+        let span_count = self.file_layer.span_count(handle)?;
+        let first = self.file_layer.span_index(handle, offset)?;
+        let region_start = self.file_layer.span_offset(handle, first)?;
+
+        // the region is extended to the end of the span containing `end`, so that it ends on a span boundary
+        let mut next = span_count;
+        let (mut buffer, _) = self.read_range(handle, region_start, offset - region_start)?;
+        buffer.extend_from_slice(data);
+        if end < size {
+            next = self.file_layer.span_index(handle, end)? + 1;
+            let next_offset = self.file_layer.span_offset(handle, next)?;
+            buffer.extend(self.read_range(handle, end, next_offset - end)?.0);
+        }
+
+        let mut info = SpansInfo {
+            spans: vec![],
+            measurements: Default::default(),
+        };
+        let mut position = region_start;
+        let mut continued = false;
+        let region_end = loop {
+            for segment in buffer.chunks(self.segment_size) {
+                let written = self
+                    .storage
+                    .write(segment, &mut handle.chunker, continued)?;
+                continued = true;
+                position += written.spans.iter().map(|span| span.length).sum::<usize>();
+                info.spans.extend(written.spans);
+                info.measurements += written.measurements;
+            }
+
+            // a write past the end of the file still has its tail in the chunker's remainder, which is flushed below
+            let fed_end = self.file_layer.span_offset(handle, next)?;
+            if position == fed_end && end <= size {
+                break next;
+            }
+
+            // a new chunk boundary matches an old one, the rest of the old spans stay the same
+            if position >= end {
+                let index = self.file_layer.span_index(handle, position)?;
+                if self.file_layer.span_offset(handle, index)? == position {
+                    break index;
+                }
+            }
+
+            if next == span_count {
+                let flushed = self.storage.flush(&mut handle.chunker)?;
+                info.spans.extend(flushed.spans);
+                info.measurements += flushed.measurements;
+                break span_count;
+            }
+
+            let next_end = self.file_layer.span_offset(handle, next + 1)?;
+            (buffer, _) = self.read_range(handle, fed_end, next_end - fed_end)?;
+            next += 1;
+        };
+
+        let record = Record::Splice {
+            name: handle.name().to_string(),
+            range: first..region_end,
+            spans: info.spans.clone(),
+        };
+        let replaced = self.file_layer.splice(handle, first..region_end, info)?;
+        self.commit(record)?;
+        self.storage.release(&replaced);
+        if end > size {
+            handle.seek(end);
+        }
+        Ok(())
+    }
+
     /// Reads all contents of the file from beginning to end and returns them.
     pub fn read_file_complete<C: Chunker>(&self, handle: &FileHandle<C>) -> io::Result<Vec<u8>> {
-        let hashes = self.file_layer.read_complete(handle);
-        Ok(self.storage.retrieve(&hashes)?.concat()) // it assumes that all retrieved data segments are in correct order
+        let mut measurements = ReadMeasurements::default();
+
+        let start = Instant::now();
+        let hashes = self.file_layer.read_complete(handle)?;
+        measurements.lookup_time = start.elapsed();
+
+        let chunks = self.storage.retrieve_measured(&hashes, &mut measurements)?;
+
+        let start = Instant::now();
+        let data = chunks.concat(); // it assumes that all retrieved data segments are in correct order
+        measurements.concat_time = start.elapsed();
+        measurements.read_bytes = data.len();
+
+        handle.add_read_measurements(measurements);
+        Ok(data)
     }
 
-    /// Reads 1 MB of data from a file and returns it.
+    /// Reads a [segment][FileSystem::segment_size] of data from a file, starting at the handle's offset, and returns it.
+    /// The handle's offset is moved past the read data.
     pub fn read_from_file<C: Chunker>(
         &mut self,
         handle: &mut FileHandle<C>,
     ) -> io::Result<Vec<u8>> {
-        let hashes = self.file_layer.read(handle);
-        Ok(self.storage.retrieve(&hashes)?.concat())
+        let data = self.read_at(handle, handle.offset(), self.segment_size)?;
+        handle.seek(handle.offset() + data.len());
+        Ok(data)
+    }
+
+    /// Reads at most `len` bytes of the file, starting at `offset`, and returns them.
+    /// Only the chunks overlapping with the requested range are retrieved from the storage.
+    ///
+    /// Returns less than `len` bytes if the end of the file is reached.
+    pub fn read_at<C: Chunker>(
+        &self,
+        handle: &FileHandle<C>,
+        offset: usize,
+        len: usize,
+    ) -> io::Result<Vec<u8>> {
+        let (data, measurements) = self.read_range(handle, offset, len)?;
+        handle.add_read_measurements(measurements);
+        Ok(data)
+    }
+
+    /// Reads at most `len` bytes of the file, starting at `offset`, and returns them with the [`ReadMeasurements`].
+    fn read_range<C: Chunker>(
+        &self,
+        handle: &FileHandle<C>,
+        offset: usize,
+        len: usize,
+    ) -> io::Result<(Vec<u8>, ReadMeasurements)> {
+        let mut measurements = ReadMeasurements::default();
+        let (first, chunks) = self.retrieve_range(handle, offset, len, &mut measurements)?;
+        let data = concat_range(chunks, offset - first, len, &mut measurements);
+        Ok((data, measurements))
+    }
+
+    /// Retrieves chunks that overlap with `len` bytes of the file, starting at `offset`,
+    /// and returns them along with the offset of the first one in the file.
+    pub(crate) fn retrieve_range<C: Chunker>(
+        &self,
+        handle: &FileHandle<C>,
+        offset: usize,
+        len: usize,
+        measurements: &mut ReadMeasurements,
+    ) -> io::Result<(usize, Vec<Vec<u8>>)> {
+        let start = Instant::now();
+        let (first, hashes) = self.file_layer.read_at(handle, offset, len)?;
+        measurements.lookup_time += start.elapsed();
+
+        let chunks = self.storage.retrieve_measured(&hashes, measurements)?;
+        Ok((first, chunks))
+    }
+
+    /// Stores a segment of data written to the end of the file, which was already chunked and hashed.
+    pub(crate) fn write_hashed<C: Chunker>(
+        &mut self,
+        handle: &mut FileHandle<C>,
+        buffer: &[u8],
+        chunks: &[Chunk],
+        hashes: Vec<Hash>,
+        measurements: WriteMeasurements,
+    ) -> io::Result<()> {
+        handle
+            .uncommitted
+            .get_or_insert(self.file_layer.span_count(handle)?);
+
+        let spans = self
+            .storage
+            .write_hashed(buffer, chunks, hashes, measurements)?;
+        handle.remainder_pending = true;
+        self.file_layer.write(handle, spans)?;
+        Ok(())
+    }
+
+    /// Returns the hasher that is used to hash written data.
+    pub(crate) fn hasher(&self) -> &H {
+        self.storage.hasher()
+    }
+
+    /// Computes deduplication statistics from the spans of all files and the contents of the database.
+    ///
+    /// Iterates over the whole database, so it may take a while if the database is large.
+    pub fn stats(&mut self) -> io::Result<FileSystemStats> {
+        let mut stats = FileSystemStats {
+            logical_bytes: self.file_layer.total_size(),
+            ..Default::default()
+        };
+
+        let mut sizes = HashMap::new();
+        for (hash, length) in self.file_layer.span_lengths() {
+            stats.span_count += 1;
+            sizes.insert(hash, length);
+        }
+        stats.duplicate_chunks = stats.span_count - sizes.len();
+        stats.add_chunk_sizes(sizes.into_values());
+
+        self.storage.collect_stats(&mut stats)?;
+        Ok(stats)
     }
 
     pub fn scrub(&mut self) -> io::Result<ScrubMeasurements> {
-        self.storage.scrub()
+        self.commit(Record::ScrubStarted)?;
+        let measurements = self.storage.scrub()?;
+        self.commit(Record::ScrubFinished)?;
+        Ok(measurements)
+    }
+
+    /// Returns total size of the chunks written since the last scrub that are not scrubbed yet (in bytes).
+    pub fn pending_scrub_data(&self) -> usize {
+        self.storage.pending_bytes()
+    }
+
+    /// Returns time passed since data was last written to the file system, or since it was created or opened.
+    pub fn idle_time(&self) -> Duration {
+        self.storage.idle_time()
+    }
+
+    /// Returns the counters behind [`pending_scrub_data`][FileSystem::pending_scrub_data]
+    /// and [`idle_time`][FileSystem::idle_time], which can be read without locking a shared file system.
+    pub(crate) fn write_activity(&self) -> Arc<WriteActivity> {
+        self.storage.activity()
+    }
+
+    /// Scrubs only the chunks written since the last scrub, oldest first, until the `budget` runs out.
+    /// Calling it again continues with the chunks that were not processed, the amount of which
+    /// is reported in the [measurements][ScrubMeasurements::pending_chunks].
+    ///
+    /// Processed chunks are recorded in the journal, so that scrubbing continues from the same chunk
+    /// after the file system is [reopened][FileSystem::open_existing].
+    ///
+    /// Returns `ErrorKind::Unsupported`, if the scrubber doesn't support [scrubbing single chunks][Scrub::scrub_chunk].
+    pub fn scrub_incremental(&mut self, budget: ScrubBudget) -> io::Result<ScrubMeasurements> {
+        let (measurements, processed) = self.storage.scrub_incremental(budget)?;
+        if !processed.is_empty() {
+            self.commit(Record::Scrubbed(processed))?;
+        }
+        Ok(measurements)
     }
 }
+
+/// Spans of a file replaced through an open handle, which stay in the journaled state of the file
+/// until the changes made through the handle, starting at the span with index `start`, are recorded.
+struct ReleasedSpans<Hash: ChunkHash> {
+    name: String,
+    start: usize,
+    hashes: Vec<Hash>,
+}
+
+/// Concatenates retrieved `chunks` and keeps at most `len` bytes of them, starting at `skip`.
+pub(crate) fn concat_range(
+    chunks: Vec<Vec<u8>>,
+    skip: usize,
+    len: usize,
+    measurements: &mut ReadMeasurements,
+) -> Vec<u8> {
+    let start = Instant::now();
+    let mut data = chunks.concat();
+    data.truncate(skip.saturating_add(len));
+    data.drain(..skip.min(data.len()));
+    measurements.concat_time += start.elapsed();
+    measurements.read_bytes += data.len();
+    data
+}
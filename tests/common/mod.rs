@@ -0,0 +1,17 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+pub const MB: usize = 1024 * 1024;
+
+/// Returns `len` pseudo-random bytes, which are the same for the same `seed`.
+pub fn random_data(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
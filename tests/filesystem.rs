@@ -1,12 +1,21 @@
 extern crate chunkfs;
 
 use std::collections::HashMap;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
 
 use chunkfs::chunkers::{FSChunker, LeapChunker};
-use chunkfs::hashers::SimpleHasher;
-use chunkfs::FileSystem;
+use chunkfs::compressors::{Lz4Compressor, SimpleCompressor, ZstdCompressor};
+use chunkfs::hashers::{Sha256Hasher, SimpleHasher};
+use chunkfs::{
+    Compressor, DeltaScrubber, DiskDatabase, DiskTargetMap, FileStream, FileSystem, OpenMode,
+    ScrubBudget, SubChunkScrubber,
+};
 
-const MB: usize = 1024 * 1024;
+use common::{random_data, MB};
+
+mod common;
 
 #[test]
 fn write_read_complete_test() {
@@ -21,7 +30,9 @@ fn write_read_complete_test() {
     let measurements = fs.close_file(handle).unwrap();
     println!("{:?}", measurements);
 
-    let handle = fs.open_file("file", LeapChunker::default()).unwrap();
+    let handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Read)
+        .unwrap();
     let read = fs.read_file_complete(&handle).unwrap();
     assert_eq!(read.len(), MB * 2);
     assert_eq!(read, [1; MB * 2]);
@@ -44,7 +55,9 @@ fn write_read_blocks_test() {
     let measurements = fs.close_file(handle).unwrap();
     println!("{:?}", measurements);
 
-    let mut handle = fs.open_file("file", LeapChunker::default()).unwrap();
+    let mut handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Read)
+        .unwrap();
     assert_eq!(fs.read_from_file(&mut handle).unwrap(), ones);
     assert_eq!(fs.read_from_file(&mut handle).unwrap(), twos);
     assert_eq!(fs.read_from_file(&mut handle).unwrap(), threes);
@@ -63,7 +76,9 @@ fn read_file_with_size_less_than_1mb() {
     let measurements = fs.close_file(handle).unwrap();
     println!("{:?}", measurements);
 
-    let mut handle = fs.open_file("file", LeapChunker::default()).unwrap();
+    let mut handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Read)
+        .unwrap();
     assert_eq!(fs.read_from_file(&mut handle).unwrap(), ones);
 }
 
@@ -79,7 +94,9 @@ fn write_read_big_file_at_once() {
     fs.write_to_file(&mut handle, &data).unwrap();
     fs.close_file(handle).unwrap();
 
-    let mut handle = fs.open_file("file", LeapChunker::default()).unwrap();
+    let mut handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Read)
+        .unwrap();
     assert_eq!(
         fs.read_file_complete(&mut handle).unwrap().len(),
         data.len()
@@ -92,13 +109,1327 @@ fn scrub_compiles_on_cdc_map() {
     let _ = fs.scrub();
 }
 
-//#[test]
+#[test]
 fn two_file_handles_to_one_file() {
     let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
     let mut handle1 = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let mut handle2 = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    fs.write_to_file(&mut handle1, &[1; MB + 100]).unwrap();
+
+    // the end of the file, held by the chunker, is stored once the writing handle is closed
+    assert_eq!(fs.read_from_file(&mut handle2).unwrap().len(), MB);
+    assert!(fs.read_from_file(&mut handle2).unwrap().is_empty());
+    fs.close_file(handle1).unwrap();
+    assert_eq!(fs.read_from_file(&mut handle2).unwrap(), [1; 100]);
+}
+
+#[test]
+fn deleted_file_cannot_be_opened() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &[1; MB]).unwrap();
+    fs.close_file(handle).unwrap();
+
+    fs.delete_file("file").unwrap();
+    assert!(!fs.file_exists("file"));
+
+    let result = fs.open_file("file", FSChunker::new(4096), OpenMode::Read);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(
+        fs.delete_file("file").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn handles_to_deleted_or_renamed_file_return_not_found() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut writer = fs
+        .create_file("deleted".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut writer, &[1; 10_000]).unwrap();
+    let reader = fs
+        .open_file("deleted", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    fs.delete_file("deleted").unwrap();
+
+    let error = fs.write_to_file(&mut writer, &[1; 10]).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+    assert_eq!(
+        fs.read_at(&reader, 0, 10).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(
+        fs.close_file(writer).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+
+    let mut writer = fs
+        .create_file("old".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut writer, &[2; 10_000]).unwrap();
+    fs.close_file(writer).unwrap();
+    let reader = fs
+        .open_file("old", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    fs.rename_file("old", "new".to_string()).unwrap();
+
+    assert_eq!(
+        fs.read_file_complete(&reader).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(
+        fs.read_at(&reader, 0, 10).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    fs.close_file(reader).unwrap();
+
+    // the renamed file is not affected by the stale handle
+    let handle = fs
+        .open_file("new", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), [2; 10_000]);
+}
+
+#[test]
+fn shared_chunks_survive_deletion_of_one_file() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let data = vec![1; MB + 10];
+    for name in ["first", "second"] {
+        let mut handle = fs
+            .create_file(name.to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &data).unwrap();
+        fs.close_file(handle).unwrap();
+    }
+
+    fs.delete_file("first").unwrap();
+
+    let handle = fs
+        .open_file("second", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn renamed_file_keeps_contents() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("old".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let data = vec![3; MB + 100];
+    fs.write_to_file(&mut handle, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    fs.rename_file("old", "new".to_string()).unwrap();
+    assert!(!fs.file_exists("old"));
+
+    let handle = fs
+        .open_file("new", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(handle.name(), "new");
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn write_read_file_in_nested_directory() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+    fs.mkdir("src").unwrap();
+    fs.mkdir("src/bin").unwrap();
+
+    let mut handle = fs
+        .create_file("src/bin/main".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let data = vec![5; MB];
+    fs.write_to_file(&mut handle, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    assert_eq!(fs.list_dir("src").unwrap(), vec!["bin"]);
+    assert_eq!(fs.list_dir("src/bin").unwrap(), vec!["main"]);
+    assert_eq!(
+        fs.remove_dir("src/bin").unwrap_err().kind(),
+        ErrorKind::DirectoryNotEmpty
+    );
+
+    let handle = fs
+        .open_file("/src/bin/main", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+
+    let result = fs.create_file("docs/readme".to_string(), FSChunker::new(4096), true);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn read_at_returns_exact_ranges() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let data = (0..2 * MB + 1000)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    fs.write_to_file(&mut handle, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    for (offset, len) in [
+        (0, 10),
+        (4000, 200),
+        (4096, 4096),
+        (MB - 7, 20),
+        (2 * MB, 1000),
+    ] {
+        assert_eq!(
+            fs.read_at(&handle, offset, len).unwrap(),
+            &data[offset..offset + len]
+        );
+    }
+
+    assert_eq!(
+        fs.read_at(&handle, 2 * MB + 900, 500).unwrap(),
+        &data[2 * MB + 900..]
+    );
+    assert!(fs.read_at(&handle, 3 * MB, 10).unwrap().is_empty());
+}
+
+#[test]
+fn read_from_file_after_seek() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let data = (0..MB + 5000).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+    fs.write_to_file(&mut handle, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let mut handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    handle.seek(3000);
+    assert_eq!(
+        fs.read_from_file(&mut handle).unwrap(),
+        &data[3000..3000 + MB]
+    );
+    assert_eq!(fs.read_from_file(&mut handle).unwrap(), &data[3000 + MB..]);
+    assert!(fs.read_from_file(&mut handle).unwrap().is_empty());
+}
+
+#[test]
+fn seek_does_not_change_where_data_is_written() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let data = (0..MB).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+    fs.write_to_file(&mut handle, &data).unwrap();
+    handle.seek(1000);
+    assert_eq!(fs.read_from_file(&mut handle).unwrap(), &data[1000..]);
+    handle.seek(1000);
+    fs.write_to_file(&mut handle, &data).unwrap();
+    assert_eq!(handle.offset(), fs.file_size("file").unwrap());
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(
+        fs.read_file_complete(&handle).unwrap(),
+        [data.clone(), data].concat()
+    );
+}
+
+fn patterned_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn write_at_overwrites_middle_of_file() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut data = patterned_data(3 * MB + 123);
+    let mut handle = fs
         .create_file("file".to_string(), LeapChunker::default(), true)
         .unwrap();
-    let mut handle2 = fs.open_file("file", LeapChunker::default()).unwrap();
-    fs.write_to_file(&mut handle1, &[1; MB]).unwrap();
-    assert_eq!(fs.read_from_file(&mut handle2).unwrap().len(), MB)
+    fs.write_to_file(&mut handle, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let mut handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Append)
+        .unwrap();
+    for (offset, len) in [(MB + 100, 5000), (0, 10), (2 * MB, MB)] {
+        let patch = vec![7; len];
+        fs.write_at(&mut handle, offset, &patch).unwrap();
+        data[offset..offset + len].copy_from_slice(&patch);
+    }
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.file_size("file").unwrap(), data.len());
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn write_at_extends_file() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let mut data = patterned_data(MB);
+    fs.write_to_file(&mut handle, &data).unwrap();
+
+    let patch = vec![1; 10000];
+    fs.write_at(&mut handle, MB - 50, &patch).unwrap();
+    data.truncate(MB - 50);
+    data.extend_from_slice(&patch);
+
+    let result = fs.write_at(&mut handle, data.len() + 1, &patch);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn write_at_to_empty_file() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let data = patterned_data(10000);
+    fs.write_at(&mut handle, 0, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn write_at_extends_file_at_chunk_boundary() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("small".to_string(), FSChunker::new(4), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &[1; 8]).unwrap();
+    fs.write_at(&mut handle, 8, &[9, 9]).unwrap();
+    assert_eq!(handle.offset(), 10);
+    assert_eq!(
+        fs.read_file_complete(&handle).unwrap(),
+        [1, 1, 1, 1, 1, 1, 1, 1, 9, 9]
+    );
+    fs.close_file(handle).unwrap();
+
+    // the size of the file is a multiple of the chunk size, and the write starts at a boundary inside it
+    let mut handle = fs
+        .create_file("large".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let mut data = patterned_data(3 * 4096);
+    fs.write_to_file(&mut handle, &data).unwrap();
+    let patch = vec![7; 5000];
+    fs.write_at(&mut handle, data.len(), &patch).unwrap();
+    data.extend_from_slice(&patch);
+    fs.write_at(&mut handle, 4096, &patch).unwrap();
+    data[4096..4096 + patch.len()].copy_from_slice(&patch);
+    fs.write_at(&mut handle, data.len() - 1000, &patch).unwrap();
+    data.truncate(data.len() - 1000);
+    data.extend_from_slice(&patch);
+    assert_eq!(fs.file_size("large").unwrap(), data.len());
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("large", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn write_to_file_after_extending_with_write_at() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let mut data = patterned_data(MB);
+    fs.write_to_file(&mut handle, &data).unwrap();
+
+    let patch = vec![1; 10000];
+    fs.write_at(&mut handle, MB - 50, &patch).unwrap();
+    assert_eq!(handle.offset(), MB - 50 + patch.len());
+    fs.write_to_file(&mut handle, &patch).unwrap();
+    fs.close_file(handle).unwrap();
+    data.truncate(MB - 50);
+    data.extend_from_slice(&patch);
+    data.extend_from_slice(&patch);
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn truncate_shrinks_file() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let data = patterned_data(2 * MB + 500);
+    let mut handle = fs
+        .create_file("file".to_string(), LeapChunker::default(), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let result = fs.truncate("file", data.len() + 1);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    for len in [MB + 12345, MB, 4096, 0] {
+        fs.truncate("file", len).unwrap();
+        assert_eq!(fs.file_size("file").unwrap(), len);
+
+        let handle = fs
+            .open_file("file", LeapChunker::default(), OpenMode::Read)
+            .unwrap();
+        assert_eq!(fs.read_file_complete(&handle).unwrap(), &data[..len]);
+    }
+}
+
+#[test]
+fn append_mode_continues_file() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let data = patterned_data(3 * MB + 777);
+    let mut handle = fs
+        .create_file("file".to_string(), LeapChunker::default(), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &data[..MB + 300]).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let mut handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Append)
+        .unwrap();
+    assert_eq!(handle.offset(), fs.file_size("file").unwrap());
+    fs.write_to_file(&mut handle, &data[MB + 300..]).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn append_handle_keeps_file_until_written() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let data = patterned_data(10000);
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let appending = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Append)
+        .unwrap();
+    assert_eq!(appending.offset(), data.len());
+    assert_eq!(fs.file_size("file").unwrap(), data.len());
+    let reader = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&reader).unwrap(), data);
+    fs.close_file(appending).unwrap();
+    assert_eq!(fs.read_file_complete(&reader).unwrap(), data);
+}
+
+#[test]
+fn renaming_or_deleting_appended_file_releases_its_chunks() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    for delete in [false, true] {
+        let mut handle = fs
+            .create_file("file".to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &patterned_data(10000))
+            .unwrap();
+        fs.close_file(handle).unwrap();
+
+        // the last span of the file is moved to the chunker of the handle
+        let mut handle = fs
+            .open_file("file", FSChunker::new(4096), OpenMode::Append)
+            .unwrap();
+        fs.write_to_file(&mut handle, &[1; 100]).unwrap();
+        if delete {
+            fs.delete_file("file").unwrap();
+        } else {
+            fs.rename_file("file", "renamed".to_string()).unwrap();
+            fs.delete_file("renamed").unwrap();
+        }
+        assert!(fs.close_file(handle).is_err());
+        assert_eq!(fs.stats().unwrap().chunk_count, 0);
+    }
+}
+
+#[test]
+fn truncate_mode_replaces_contents() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &[1; MB]).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let mut handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Truncate)
+        .unwrap();
+    assert_eq!(fs.file_size("file").unwrap(), 0);
+    fs.write_to_file(&mut handle, &[2; 100]).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), [2; 100]);
+}
+
+#[test]
+fn cant_write_to_file_opened_for_reading() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.close_file(handle).unwrap();
+
+    let mut handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    let result = fs.write_to_file(&mut handle, &[1; 10]);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn write_read_with_disk_database() {
+    let path = std::env::temp_dir().join(format!("chunkfs-fs-disk-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+
+    let data = patterned_data(2 * MB + 10);
+    {
+        let database = DiskDatabase::create(&path).unwrap();
+        let mut fs = FileSystem::new_cdc_only(database, Sha256Hasher::default());
+
+        let mut handle = fs
+            .create_file("file".to_string(), LeapChunker::default(), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &data).unwrap();
+        fs.close_file(handle).unwrap();
+
+        let handle = fs
+            .open_file("file", LeapChunker::default(), OpenMode::Read)
+            .unwrap();
+        assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+        fs.scrub().unwrap();
+    }
+
+    let database = DiskDatabase::open(&path).unwrap();
+    assert!(!database.is_empty());
+    let _: FileSystem<_, _, _, i32> = FileSystem::new_cdc_only(database, Sha256Hasher::default());
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn reopen_existing_file_system() {
+    let path = std::env::temp_dir().join(format!("chunkfs-fs-reopen-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let metadata = path.join("metadata");
+
+    let data = patterned_data(MB + 500);
+    {
+        let database = DiskDatabase::create(path.join("chunks")).unwrap();
+        let mut fs = FileSystem::new_cdc_only(database, Sha256Hasher::default());
+        fs.mkdir("dir").unwrap();
+
+        let mut handle = fs
+            .create_file("dir/file".to_string(), LeapChunker::default(), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &data).unwrap();
+        fs.close_file(handle).unwrap();
+        fs.create_file("empty".to_string(), LeapChunker::default(), true)
+            .unwrap();
+
+        fs.save_metadata(&metadata).unwrap();
+    }
+
+    let database = DiskDatabase::open(path.join("chunks")).unwrap();
+    let mut fs =
+        FileSystem::open_existing_cdc_only(database, Sha256Hasher::default(), &metadata).unwrap();
+    assert_eq!(fs.list_dir("").unwrap(), ["dir", "empty"]);
+    assert_eq!(fs.file_size("empty").unwrap(), 0);
+
+    let handle = fs
+        .open_file("dir/file", LeapChunker::default(), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+
+    // reference counts are restored, so deleting the file removes its chunks
+    fs.delete_file("dir/file").unwrap();
+    fs.save_metadata(&metadata).unwrap();
+    drop(fs);
+    assert!(DiskDatabase::<Vec<u8>, i32>::open(path.join("chunks"))
+        .unwrap()
+        .is_empty());
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn open_existing_with_missing_chunks() {
+    let path = std::env::temp_dir().join(format!("chunkfs-fs-missing-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    let metadata = path.join("metadata");
+
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &[1; 10000]).unwrap();
+    fs.close_file(handle).unwrap();
+    fs.save_metadata(&metadata).unwrap();
+
+    let result = FileSystem::open_existing_cdc_only(HashMap::default(), SimpleHasher, &metadata);
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn reopen_scrubbed_file_system() {
+    let path = std::env::temp_dir().join(format!("chunkfs-fs-scrubbed-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let metadata = path.join("metadata");
+
+    let original = random_data(MB, 1);
+    let mut similar = original.clone();
+    for i in (100..similar.len()).step_by(4096) {
+        similar[i] ^= 0xff;
+    }
+    {
+        let mut fs = FileSystem::new(
+            DiskDatabase::create(path.join("chunks")).unwrap(),
+            Box::new(DiskTargetMap::<u64>::create(path.join("targets")).unwrap()),
+            Box::new(DeltaScrubber::new()),
+            Sha256Hasher::default(),
+        );
+        let mut handle = fs
+            .create_file("original".to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &original).unwrap();
+        fs.close_file(handle).unwrap();
+        fs.scrub().unwrap();
+        fs.save_metadata(&metadata).unwrap();
+    }
+
+    // the data of the scrubbed chunks is missing from an empty target map
+    let result = FileSystem::open_existing(
+        DiskDatabase::<_, u64>::open(path.join("chunks")).unwrap(),
+        Box::<HashMap<u64, Vec<u8>>>::default(),
+        Box::new(DeltaScrubber::new()),
+        Sha256Hasher::default(),
+        &metadata,
+    );
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+
+    let mut fs = FileSystem::open_existing(
+        DiskDatabase::open(path.join("chunks")).unwrap(),
+        Box::new(DiskTargetMap::<u64>::open(path.join("targets")).unwrap()),
+        Box::new(DeltaScrubber::new()),
+        Sha256Hasher::default(),
+        &metadata,
+    )
+    .unwrap();
+    // keys used before reopening are not reused for the chunks scrubbed now
+    let mut handle = fs
+        .create_file("similar".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &similar).unwrap();
+    fs.close_file(handle).unwrap();
+    fs.scrub().unwrap();
+
+    for (name, data) in [("original", &original), ("similar", &similar)] {
+        let handle = fs
+            .open_file(name, FSChunker::new(4096), OpenMode::Read)
+            .unwrap();
+        assert_eq!(&fs.read_file_complete(&handle).unwrap(), data);
+    }
+
+    drop(fs);
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn stats_count_duplicate_chunks() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+    let data = patterned_data(10000);
+
+    for name in ["a", "b"] {
+        let mut handle = fs
+            .create_file(name.to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &data).unwrap();
+        fs.close_file(handle).unwrap();
+    }
+
+    let stats = fs.stats().unwrap();
+    assert_eq!(stats.logical_bytes, 20000);
+    assert_eq!(stats.unique_bytes, 10000);
+    assert_eq!(stats.target_bytes, 0);
+    assert_eq!(stats.chunk_count, 3);
+    assert_eq!(stats.span_count, 6);
+    assert_eq!(stats.duplicate_chunks, 3);
+    assert_eq!(stats.min_chunk_size, 10000 - 2 * 4096);
+    assert_eq!(stats.max_chunk_size, 4096);
+    assert_eq!(stats.dedup_ratio(), 2.0);
+}
+
+#[test]
+fn write_measurements_accumulate_per_file() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &[1; MB]).unwrap();
+    fs.write_to_file(&mut handle, &patterned_data(MB + 100))
+        .unwrap();
+    let measurements = fs.close_file(handle).unwrap();
+
+    assert_eq!(measurements.written_bytes(), 2 * MB + 100);
+    assert_eq!(measurements.chunk_count(), (2 * MB + 100).div_ceil(4096));
+    assert_eq!(
+        measurements.new_bytes() + measurements.duplicate_bytes(),
+        2 * MB + 100
+    );
+    assert!(measurements.duplicate_bytes() >= MB - 4096);
+}
+
+#[test]
+fn read_measurements_accumulate_on_close() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+    let data = patterned_data(3 * MB);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &data).unwrap();
+    fs.close_file(handle).unwrap();
+
+    let mut handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    fs.read_from_file(&mut handle).unwrap();
+    fs.read_at(&handle, 100, 1000).unwrap();
+    assert_eq!(handle.read_measurements().read_bytes(), MB + 1000);
+
+    fs.read_file_complete(&handle).unwrap();
+    assert_eq!(handle.read_measurements().read_bytes(), 4 * MB + 1000);
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    fs.read_at(&handle, 0, 10).unwrap();
+    fs.close_file(handle).unwrap();
+    assert_eq!(fs.read_measurements().read_bytes(), 4 * MB + 1010);
+}
+
+#[test]
+fn stream_copies_file_in_and_out() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+    let data = patterned_data(3 * MB + 12345);
+
+    let mut handle = fs
+        .create_file("file".to_string(), LeapChunker::default(), true)
+        .unwrap();
+    let mut stream = FileStream::new(&mut fs, &mut handle);
+    io::copy(&mut data.as_slice(), &mut stream).unwrap();
+    stream.flush().unwrap();
+    drop(stream);
+    fs.close_file(handle).unwrap();
+
+    let mut handle = fs
+        .open_file("file", LeapChunker::default(), OpenMode::Read)
+        .unwrap();
+    let mut stream = FileStream::new(&mut fs, &mut handle);
+    let mut read = vec![];
+    io::copy(&mut stream, &mut read).unwrap();
+    assert_eq!(read, data);
+
+    stream.seek(SeekFrom::Start(MB as u64 - 10)).unwrap();
+    let mut buf = [0; 100];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[MB - 10..MB + 90]);
+
+    assert_eq!(
+        stream.seek(SeekFrom::End(-5)).unwrap(),
+        data.len() as u64 - 5
+    );
+    let mut tail = vec![];
+    stream.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail, data[data.len() - 5..]);
+}
+
+#[test]
+fn stream_overwrites_and_appends() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+    let mut data = patterned_data(2 * MB);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let mut stream = FileStream::new(&mut fs, &mut handle);
+    stream.write_all(&data).unwrap();
+
+    // reading the end of the file, which is still held by the chunker
+    stream.seek(SeekFrom::Start(2 * MB as u64 - 100)).unwrap();
+    let mut buf = [0; 100];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[2 * MB - 100..]);
+
+    stream.seek(SeekFrom::Start(1000)).unwrap();
+    stream.write_all(&[1; 5000]).unwrap();
+    data[1000..6000].fill(1);
+
+    stream.seek(SeekFrom::End(0)).unwrap();
+    stream.write_all(&[2; 3000]).unwrap();
+    data.extend_from_slice(&[2; 3000]);
+
+    let error = stream
+        .seek(SeekFrom::Current(1))
+        .and_then(|_| stream.write_all(&[3]));
+    assert_eq!(error.unwrap_err().kind(), ErrorKind::InvalidInput);
+    drop(stream);
+    fs.close_file(handle).unwrap();
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+}
+
+#[test]
+fn stream_reads_overwritten_data() {
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &[1; 10]).unwrap();
+    let mut stream = FileStream::new(&mut fs, &mut handle);
+    let mut buf = [0; 10];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [1; 10]);
+
+    // the overwritten range was read before, so it is cached by the stream
+    stream.seek(SeekFrom::Start(0)).unwrap();
+    stream.write_all(&[2; 10]).unwrap();
+    stream.seek(SeekFrom::Start(0)).unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [2; 10]);
+
+    stream.seek(SeekFrom::Start(3)).unwrap();
+    stream.write_all(&[3; 2]).unwrap();
+    stream.seek(SeekFrom::Start(0)).unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [2, 2, 2, 3, 3, 2, 2, 2, 2, 2]);
+}
+
+#[test]
+fn write_read_with_different_segment_sizes() {
+    let data = patterned_data(MB + 777);
+    for segment_size in [100, 4096, 5000, 64 * 1024, 3 * MB] {
+        let mut fs = FileSystem::new_cdc_only(HashMap::default(), SimpleHasher);
+        fs.set_segment_size(segment_size);
+
+        let mut handle = fs
+            .create_file("file".to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &data).unwrap();
+        fs.write_at(&mut handle, 1000, &[1; 9000]).unwrap();
+        fs.close_file(handle).unwrap();
+
+        let mut expected = data.clone();
+        expected[1000..10000].fill(1);
+
+        let mut handle = fs
+            .open_file("file", FSChunker::new(4096), OpenMode::Read)
+            .unwrap();
+        let mut read = vec![];
+        loop {
+            let segment = fs.read_from_file(&mut handle).unwrap();
+            if segment.is_empty() {
+                break;
+            }
+            assert!(segment.len() <= segment_size);
+            read.extend(segment);
+        }
+        assert_eq!(read, expected, "segment size {segment_size}");
+
+        let mut stream = FileStream::new(&mut fs, &mut handle);
+        let mut streamed = vec![];
+        stream.read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, expected, "segment size {segment_size}");
+    }
+}
+
+#[test]
+fn parallel_hashing_produces_same_spans() {
+    let data = patterned_data(3 * MB + 100);
+    let mut results = vec![];
+    for threads in [1, 4] {
+        let mut fs = FileSystem::new_cdc_only(HashMap::default(), Sha256Hasher::default());
+        fs.set_hash_threads(threads);
+
+        let mut handle = fs
+            .create_file("file".to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &data).unwrap();
+        let measurements = fs.close_file(handle).unwrap();
+        assert_eq!(measurements.written_bytes(), data.len());
+        assert!(measurements.hash_cpu_time() > Duration::ZERO);
+
+        let handle = fs
+            .open_file("file", FSChunker::new(4096), OpenMode::Read)
+            .unwrap();
+        assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+        results.push(fs.stats().unwrap());
+    }
+    assert_eq!(results[0], results[1]);
+}
+
+#[test]
+fn pipelined_write_matches_serial_write() {
+    let data = patterned_data(5 * MB + 321);
+    let mut results = vec![];
+    for pipelined in [false, true] {
+        let mut fs = FileSystem::new_cdc_only(HashMap::default(), Sha256Hasher::default());
+        fs.set_segment_size(256 * 1024);
+        fs.set_hash_threads(3);
+
+        let mut handle = fs
+            .create_file("file".to_string(), FSChunker::new(5000), true)
+            .unwrap();
+        for part in data.chunks(2 * MB) {
+            match pipelined {
+                true => fs.write_to_file_pipelined(&mut handle, part).unwrap(),
+                false => fs.write_to_file(&mut handle, part).unwrap(),
+            }
+        }
+        let measurements = fs.close_file(handle).unwrap();
+        assert_eq!(measurements.written_bytes(), data.len());
+
+        let handle = fs
+            .open_file("file", FSChunker::new(5000), OpenMode::Read)
+            .unwrap();
+        assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+        results.push(fs.stats().unwrap());
+    }
+    assert_eq!(results[0], results[1]);
+}
+
+fn compression_round_trip(compressor: impl Compressor + Send + 'static) {
+    // text data is compressible, but its chunks are all different
+    let data = (0..200_000)
+        .flat_map(|i| format!("line {i}\n").into_bytes())
+        .take(MB + 100)
+        .collect::<Vec<_>>();
+
+    let mut fs = FileSystem::new_cdc_only(HashMap::default(), Sha256Hasher::default());
+    fs.set_compressor(compressor);
+
+    let mut handle = fs
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &data).unwrap();
+    let measurements = fs.close_file(handle).unwrap();
+    assert_eq!(measurements.new_bytes(), data.len());
+    assert!(measurements.compressed_bytes() < measurements.new_bytes() / 2);
+    assert!(measurements.compress_time() > Duration::ZERO);
+
+    let handle = fs
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), data);
+    assert_eq!(fs.read_at(&handle, 5000, 10).unwrap(), data[5000..5010]);
+    fs.close_file(handle).unwrap();
+    assert!(fs.read_measurements().decompress_time() > Duration::ZERO);
+}
+
+#[test]
+fn compressed_chunks_are_read_back() {
+    compression_round_trip(SimpleCompressor);
+    compression_round_trip(Lz4Compressor);
+    compression_round_trip(ZstdCompressor::default());
+}
+
+#[test]
+fn similar_files_are_delta_encoded_by_scrubber() {
+    let original = random_data(MB, 1);
+    // every chunk of the copy differs from the original one in a single byte
+    let mut similar = original.clone();
+    for i in (100..similar.len()).step_by(4096) {
+        similar[i] ^= 0xff;
+    }
+
+    let mut fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<u64, Vec<u8>>>::default(),
+        Box::new(DeltaScrubber::new()),
+        Sha256Hasher::default(),
+    );
+    for (name, data) in [("original", &original), ("similar", &similar)] {
+        let mut handle = fs
+            .create_file(name.to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, data).unwrap();
+        fs.close_file(handle).unwrap();
+    }
+    let before = fs.stats().unwrap();
+
+    let measurements = fs.scrub().unwrap();
+    assert_eq!(measurements.processed_data, 2 * MB);
+    let after = fs.stats().unwrap();
+    assert_eq!(after.unique_bytes, 0);
+    assert!(after.dedup_ratio() > 1.8 * before.dedup_ratio());
+
+    for (name, data) in [("original", &original), ("similar", &similar)] {
+        let handle = fs
+            .open_file(name, FSChunker::new(4096), OpenMode::Read)
+            .unwrap();
+        assert_eq!(&fs.read_file_complete(&handle).unwrap(), data);
+        assert_eq!(fs.read_at(&handle, 5000, 100).unwrap(), data[5000..5100]);
+    }
+}
+
+#[test]
+fn sub_chunks_are_deduplicated_by_scrubber() {
+    let shared = random_data(MB, 1);
+    // the shared data is shifted, so that large fixed-size chunks of the files differ
+    let shifted = [vec![7; 1000], shared.clone()].concat();
+
+    let mut fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<_, Vec<u8>>>::default(),
+        Box::new(SubChunkScrubber::new(
+            LeapChunker::default(),
+            Sha256Hasher::default(),
+        )),
+        Sha256Hasher::default(),
+    );
+    for (name, data) in [("shared", &shared), ("shifted", &shifted)] {
+        let mut handle = fs
+            .create_file(name.to_string(), FSChunker::new(64 * 1024), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, data).unwrap();
+        fs.close_file(handle).unwrap();
+    }
+
+    let measurements = fs.scrub().unwrap();
+    assert_eq!(measurements.processed_data, shared.len() + shifted.len());
+    assert!(measurements.saved_data > MB / 2);
+    assert_eq!(fs.stats().unwrap().unique_bytes, 0);
+
+    for (name, data) in [("shared", &shared), ("shifted", &shifted)] {
+        let handle = fs
+            .open_file(name, FSChunker::new(64 * 1024), OpenMode::Read)
+            .unwrap();
+        assert_eq!(&fs.read_file_complete(&handle).unwrap(), data);
+    }
+}
+
+#[test]
+fn stats_count_shared_target_data_once() {
+    let shared = random_data(MB, 3);
+    let shifted = [vec![7; 1000], shared.clone()].concat();
+
+    let mut fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<_, Vec<u8>>>::default(),
+        Box::new(SubChunkScrubber::new(
+            LeapChunker::default(),
+            Sha256Hasher::default(),
+        )),
+        Sha256Hasher::default(),
+    );
+    for (name, data) in [("shared", &shared), ("shifted", &shifted)] {
+        let mut handle = fs
+            .create_file(name.to_string(), FSChunker::new(64 * 1024), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, data).unwrap();
+        fs.close_file(handle).unwrap();
+    }
+
+    let measurements = fs.scrub().unwrap();
+    // every sub-chunk that was not saved is stored in the target map exactly once
+    let stats = fs.stats().unwrap();
+    assert_eq!(
+        stats.target_bytes,
+        measurements.processed_data - measurements.saved_data
+    );
+}
+
+#[test]
+fn incremental_scrub_processes_new_chunks_within_budget() {
+    let mut fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<_, Vec<u8>>>::default(),
+        Box::new(SubChunkScrubber::new(
+            LeapChunker::default(),
+            Sha256Hasher::default(),
+        )),
+        Sha256Hasher::default(),
+    );
+    let chunk_size = 64 * 1024;
+    let first = random_data(MB, 1);
+    let second = random_data(4 * chunk_size, 2);
+
+    let mut handle = fs
+        .create_file("first".to_string(), FSChunker::new(chunk_size), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &first).unwrap();
+    fs.close_file(handle).unwrap();
+
+    // the budget is checked after each chunk, so the last chunk exceeds it
+    let measurements = fs
+        .scrub_incremental(ScrubBudget::Bytes(3 * chunk_size + 1))
+        .unwrap();
+    assert_eq!(measurements.processed_data, 4 * chunk_size);
+    assert_eq!(measurements.pending_chunks, 12);
+    assert_eq!(measurements.pending_data, MB - 4 * chunk_size);
+    assert_eq!(fs.stats().unwrap().unique_bytes, MB - 4 * chunk_size);
+
+    let measurements = fs.scrub_incremental(ScrubBudget::Bytes(0)).unwrap();
+    assert_eq!(measurements.processed_data, chunk_size);
+    assert_eq!(measurements.pending_chunks, 11);
+
+    let measurements = fs.scrub_incremental(ScrubBudget::Unlimited).unwrap();
+    assert_eq!(measurements.processed_data, MB - 5 * chunk_size);
+    assert_eq!(measurements.pending_chunks, 0);
+    assert_eq!(fs.stats().unwrap().unique_bytes, 0);
+
+    // only the chunks written since the last scrub are processed
+    let mut handle = fs
+        .create_file("second".to_string(), FSChunker::new(chunk_size), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &second).unwrap();
+    fs.close_file(handle).unwrap();
+    let measurements = fs
+        .scrub_incremental(ScrubBudget::Time(Duration::from_secs(60)))
+        .unwrap();
+    assert_eq!(measurements.processed_data, second.len());
+    assert_eq!(measurements.pending_data, 0);
+
+    for (name, data) in [("first", &first), ("second", &second)] {
+        let handle = fs
+            .open_file(name, FSChunker::new(chunk_size), OpenMode::Read)
+            .unwrap();
+        assert_eq!(&fs.read_file_complete(&handle).unwrap(), data);
+    }
+}
+
+#[test]
+fn deleting_scrubbed_files_frees_target_map_data() {
+    let original = random_data(MB, 4);
+    let mut similar = original.clone();
+    for i in (100..similar.len()).step_by(4096) {
+        similar[i] ^= 0xff;
+    }
+
+    let mut fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<u64, Vec<u8>>>::default(),
+        Box::new(DeltaScrubber::new()),
+        Sha256Hasher::default(),
+    );
+    for (name, data) in [("original", &original), ("similar", &similar)] {
+        let mut handle = fs
+            .create_file(name.to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, data).unwrap();
+        fs.close_file(handle).unwrap();
+    }
+    fs.scrub().unwrap();
+    let scrubbed = fs.stats().unwrap().target_bytes;
+
+    // the bases are still used by the deltas of the similar file
+    fs.delete_file("original").unwrap();
+    let target_bytes = fs.stats().unwrap().target_bytes;
+    assert!(target_bytes < scrubbed);
+    assert!(target_bytes >= MB);
+    let handle = fs
+        .open_file("similar", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+    assert_eq!(fs.read_file_complete(&handle).unwrap(), similar);
+    fs.close_file(handle).unwrap();
+
+    fs.delete_file("similar").unwrap();
+    assert_eq!(fs.stats().unwrap().target_bytes, 0);
+}
+
+#[test]
+fn compressed_chunks_are_scrubbed_decompressed() {
+    let original = random_data(MB, 5);
+    let mut similar = original.clone();
+    for i in (100..similar.len()).step_by(4096) {
+        similar[i] ^= 0xff;
+    }
+
+    let mut fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<u64, Vec<u8>>>::default(),
+        Box::new(DeltaScrubber::new()),
+        Sha256Hasher::default(),
+    );
+    fs.set_compressor(SimpleCompressor);
+    for (name, data) in [("original", &original), ("similar", &similar)] {
+        let mut handle = fs
+            .create_file(name.to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, data).unwrap();
+        fs.close_file(handle).unwrap();
+    }
+
+    let measurements = fs.scrub().unwrap();
+    assert_eq!(measurements.processed_data, 2 * MB);
+    assert!(measurements.saved_data > MB / 2);
+    assert_eq!(fs.stats().unwrap().unique_bytes, 0);
+
+    for (name, data) in [("original", &original), ("similar", &similar)] {
+        let handle = fs
+            .open_file(name, FSChunker::new(4096), OpenMode::Read)
+            .unwrap();
+        assert_eq!(&fs.read_file_complete(&handle).unwrap(), data);
+    }
+}
+
+#[test]
+fn incremental_scrub_continues_after_reopen() {
+    let path = std::env::temp_dir().join(format!("chunkfs-fs-resume-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let metadata = path.join("metadata");
+    let chunk_size = 64 * 1024;
+    let first = random_data(MB, 1);
+    let second = random_data(4 * chunk_size, 2);
+
+    let open = || {
+        FileSystem::open_existing(
+            DiskDatabase::open(path.join("chunks")).unwrap(),
+            Box::new(DiskTargetMap::<u64>::open(path.join("targets")).unwrap()),
+            Box::new(DeltaScrubber::new()),
+            Sha256Hasher::default(),
+            &metadata,
+        )
+        .unwrap()
+    };
+    {
+        let mut fs = FileSystem::new(
+            DiskDatabase::create(path.join("chunks")).unwrap(),
+            Box::new(DiskTargetMap::<u64>::create(path.join("targets")).unwrap()),
+            Box::new(DeltaScrubber::new()),
+            Sha256Hasher::default(),
+        );
+        let mut handle = fs
+            .create_file("first".to_string(), FSChunker::new(chunk_size), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &first).unwrap();
+        fs.close_file(handle).unwrap();
+        fs.save_metadata(&metadata).unwrap();
+
+        let measurements = fs
+            .scrub_incremental(ScrubBudget::Bytes(3 * chunk_size + 1))
+            .unwrap();
+        assert_eq!(measurements.processed_data, 4 * chunk_size);
+
+        // chunks written after saving the metadata are pending as well
+        let mut handle = fs
+            .create_file("second".to_string(), FSChunker::new(chunk_size), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &second).unwrap();
+        fs.close_file(handle).unwrap();
+    }
+
+    let mut fs = open();
+    assert_eq!(fs.pending_scrub_data(), MB - 4 * chunk_size + second.len());
+    let measurements = fs.scrub_incremental(ScrubBudget::Unlimited).unwrap();
+    assert_eq!(
+        measurements.processed_data,
+        MB - 4 * chunk_size + second.len()
+    );
+    assert_eq!(measurements.pending_chunks, 0);
+    drop(fs);
+
+    let mut fs = open();
+    assert_eq!(fs.pending_scrub_data(), 0);
+    for (name, data) in [("first", &first), ("second", &second)] {
+        let handle = fs
+            .open_file(name, FSChunker::new(chunk_size), OpenMode::Read)
+            .unwrap();
+        assert_eq!(&fs.read_file_complete(&handle).unwrap(), data);
+    }
+
+    drop(fs);
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn rewritten_chunks_are_pending_once() {
+    let mut fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<u64, Vec<u8>>>::default(),
+        Box::new(DeltaScrubber::new()),
+        Sha256Hasher::default(),
+    );
+    let data = random_data(MB, 3);
+
+    for name in ["deleted", "rewritten"] {
+        let mut handle = fs
+            .create_file(name.to_string(), FSChunker::new(4096), true)
+            .unwrap();
+        fs.write_to_file(&mut handle, &data).unwrap();
+        fs.close_file(handle).unwrap();
+        if name == "deleted" {
+            fs.delete_file(name).unwrap();
+            assert_eq!(fs.pending_scrub_data(), 0);
+        }
+    }
+    assert_eq!(fs.pending_scrub_data(), MB);
+
+    let measurements = fs.scrub_incremental(ScrubBudget::Unlimited).unwrap();
+    assert_eq!(measurements.processed_data, MB);
+    assert_eq!(measurements.pending_chunks, 0);
 }
@@ -0,0 +1,262 @@
+extern crate chunkfs;
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use chunkfs::chunkers::FSChunker;
+use chunkfs::hashers::Sha256Hasher;
+use chunkfs::{DataContainer, Database, DiskDatabase, FileSystem, Hasher, OpenMode};
+
+use common::random_data;
+
+mod common;
+
+type Hash = <Sha256Hasher as Hasher>::Hash;
+type Fs = FileSystem<FailingDatabase, Sha256Hasher, Hash, i32>;
+type Step = Box<dyn Fn(&mut Fs) -> io::Result<()>>;
+
+/// Counts down operations that change the database, and crashes once the count reaches zero.
+struct Crash {
+    remaining: Cell<usize>,
+    crashed: Cell<bool>,
+}
+
+impl Crash {
+    fn new() -> Rc<Self> {
+        Rc::new(Self {
+            remaining: Cell::new(usize::MAX),
+            crashed: Cell::new(false),
+        })
+    }
+
+    fn tick(&self) -> io::Result<()> {
+        if self.remaining.get() == 0 {
+            self.crashed.set(true);
+        }
+        if self.crashed.get() {
+            return Err(io::Error::other("crash"));
+        }
+        self.remaining.set(self.remaining.get() - 1);
+        Ok(())
+    }
+}
+
+/// [`DiskDatabase`] which stops writing anything to disk once the [`Crash`] happens.
+/// After the crash, the database is not flushed on drop.
+struct FailingDatabase {
+    inner: ManuallyDrop<DiskDatabase<Hash, i32>>,
+    crash: Rc<Crash>,
+}
+
+impl Database<Hash, DataContainer<i32>> for FailingDatabase {
+    fn insert(&mut self, key: Hash, value: DataContainer<i32>) -> io::Result<()> {
+        self.crash.tick()?;
+        self.inner.insert(key, value)
+    }
+
+    fn get(&self, key: &Hash) -> io::Result<DataContainer<i32>> {
+        self.inner.get(key)
+    }
+
+    fn remove(&mut self, key: &Hash) {
+        if self.crash.tick().is_ok() {
+            self.inner.remove(key)
+        }
+    }
+
+    fn contains(&self, key: &Hash) -> bool {
+        self.inner.contains(key)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.crash.tick()?;
+        self.inner.flush()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut FailingDatabase {
+    type Item = (&'a Hash, &'a mut DataContainer<i32>);
+    type IntoIter = <&'a mut DiskDatabase<Hash, i32> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        (&mut *self.inner).into_iter()
+    }
+}
+
+impl Drop for FailingDatabase {
+    fn drop(&mut self) {
+        if !self.crash.crashed.get() {
+            unsafe { ManuallyDrop::drop(&mut self.inner) }
+        }
+    }
+}
+
+fn append(fs: &mut Fs, name: &str, data: &[u8]) -> io::Result<()> {
+    let mut handle = fs.open_file(name, FSChunker::new(512), OpenMode::Append)?;
+    fs.write_to_file(&mut handle, data)?;
+    fs.close_file(handle)?;
+    Ok(())
+}
+
+fn workload(metadata: PathBuf) -> Vec<Step> {
+    vec![
+        Box::new(|fs| fs.mkdir("dir")),
+        Box::new(|fs| {
+            let handle = fs.create_file("dir/a".to_string(), FSChunker::new(512), false)?;
+            fs.close_file(handle).map(|_| ())
+        }),
+        Box::new(|fs| append(fs, "dir/a", &random_data(5000, 1))),
+        Box::new(|fs| append(fs, "dir/a", &random_data(3000, 2))),
+        Box::new(|fs| {
+            let mut handle = fs.open_file("dir/a", FSChunker::new(512), OpenMode::Append)?;
+            fs.write_at(&mut handle, 1000, &random_data(2000, 3))?;
+            fs.close_file(handle).map(|_| ())
+        }),
+        Box::new(|fs| fs.truncate("dir/a", 4500)),
+        Box::new(|fs| {
+            let handle = fs.create_file("b".to_string(), FSChunker::new(512), false)?;
+            fs.close_file(handle).map(|_| ())
+        }),
+        Box::new(|fs| append(fs, "b", &random_data(2000, 4))),
+        Box::new(|fs| fs.rename_file("dir/a", "c".to_string())),
+        Box::new(|fs| fs.scrub().map(|_| ())),
+        Box::new(|fs| fs.delete_file("b")),
+        Box::new(move |fs| fs.save_metadata(&metadata)),
+        Box::new(|fs| append(fs, "c", &random_data(1000, 5))),
+        Box::new(|fs| fs.delete_file("c")),
+        Box::new(|fs| fs.remove_dir("dir")),
+    ]
+}
+
+/// Returns contents of all files, and `None` for all directories in the file system.
+fn snapshot(fs: &mut Fs) -> BTreeMap<String, Option<Vec<u8>>> {
+    let mut snapshot = BTreeMap::new();
+    let mut directories = vec![String::new()];
+    while let Some(directory) = directories.pop() {
+        for name in fs.list_dir(&directory).unwrap() {
+            let path = format!("{directory}/{name}");
+            if fs.dir_exists(&path) {
+                snapshot.insert(path.clone(), None);
+                directories.push(path);
+            } else {
+                let handle = fs
+                    .open_file(&path, FSChunker::new(512), OpenMode::Read)
+                    .unwrap();
+                snapshot.insert(path, Some(fs.read_file_complete(&handle).unwrap()));
+            }
+        }
+    }
+    snapshot
+}
+
+fn create(dir: &Path, crash: Rc<Crash>) -> Fs {
+    let database = FailingDatabase {
+        inner: ManuallyDrop::new(DiskDatabase::create(dir.join("chunks")).unwrap()),
+        crash,
+    };
+    let mut fs = FileSystem::new_cdc_only(database, Sha256Hasher::default());
+    fs.save_metadata(dir.join("metadata")).unwrap();
+    fs
+}
+
+fn reopen(dir: &Path) -> Fs {
+    let database = FailingDatabase {
+        inner: ManuallyDrop::new(DiskDatabase::open(dir.join("chunks")).unwrap()),
+        crash: Crash::new(),
+    };
+    FileSystem::open_existing_cdc_only(database, Sha256Hasher::default(), dir.join("metadata"))
+        .unwrap()
+}
+
+#[test]
+fn recovers_after_crash_at_every_step() {
+    let root = std::env::temp_dir().join(format!("chunkfs-crash-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let reference = root.join("reference");
+    let steps = workload(reference.join("metadata"));
+    let mut fs = create(&reference, Crash::new());
+    let mut expected = vec![snapshot(&mut fs)];
+    for step in &steps {
+        step(&mut fs).unwrap();
+        expected.push(snapshot(&mut fs));
+    }
+    drop(fs);
+
+    for budget in 0.. {
+        let dir = root.join(budget.to_string());
+        let steps = workload(dir.join("metadata"));
+        let crash = Crash::new();
+        let mut fs = create(&dir, crash.clone());
+        crash.remaining.set(budget);
+
+        let completed = steps
+            .iter()
+            .take_while(|step| step(&mut fs).is_ok() && !crash.crashed.get())
+            .count();
+        drop(fs);
+
+        // the interrupted operation is either replayed or rolled back
+        let mut fs = reopen(&dir);
+        let state = snapshot(&mut fs);
+        assert!(
+            state == expected[completed] || expected.get(completed + 1) == Some(&state),
+            "unexpected state after crash at step {completed}, budget {budget}"
+        );
+
+        // chunks of the interrupted operations are not left in the database
+        for (path, contents) in &state {
+            if contents.is_some() {
+                fs.delete_file(path).unwrap();
+            }
+        }
+        drop(fs);
+        let database: DiskDatabase<Hash, i32> = DiskDatabase::open(dir.join("chunks")).unwrap();
+        assert!(database.is_empty(), "chunks left after budget {budget}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        if completed == steps.len() {
+            break;
+        }
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn operations_after_save_are_replayed() {
+    let dir = std::env::temp_dir().join(format!("chunkfs-replay-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut fs = create(&dir, Crash::new());
+    fs.mkdir("dir").unwrap();
+    let mut handle = fs
+        .create_file("dir/file".to_string(), FSChunker::new(512), false)
+        .unwrap();
+    fs.write_to_file(&mut handle, &random_data(3000, 1))
+        .unwrap();
+    fs.close_file(handle).unwrap();
+    drop(fs);
+
+    let mut fs = reopen(&dir);
+    assert_eq!(fs.list_dir("dir").unwrap(), ["file"]);
+    let handle = fs
+        .open_file("dir/file", FSChunker::new(512), OpenMode::Read)
+        .unwrap();
+    assert_eq!(
+        fs.read_file_complete(&handle).unwrap(),
+        random_data(3000, 1)
+    );
+
+    // reopening again continues the same journal
+    fs.rename_file("dir/file", "file".to_string()).unwrap();
+    drop(fs);
+    let fs = reopen(&dir);
+    assert!(fs.file_exists("file"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
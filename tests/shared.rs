@@ -0,0 +1,193 @@
+extern crate chunkfs;
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chunkfs::chunkers::FSChunker;
+use chunkfs::hashers::Sha256Hasher;
+use chunkfs::{FileSystem, OpenMode, ScrubBudget, ScrubPolicy, SharedFileSystem, SubChunkScrubber};
+
+use common::{random_data, MB};
+
+mod common;
+
+#[test]
+fn threads_write_distinct_files() {
+    let fs = FileSystem::new_cdc_only(HashMap::default(), Sha256Hasher::default());
+    let fs = SharedFileSystem::new(fs);
+
+    let writers = (1..=4)
+        .map(|seed| {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let name = format!("file{seed}");
+                let mut handle = fs
+                    .lock()
+                    .create_file(name, FSChunker::new(4096), true)
+                    .unwrap();
+                for part in random_data(3 * MB, seed).chunks(100_000) {
+                    fs.write_to_file(&mut handle, part).unwrap();
+                }
+                fs.lock().close_file(handle).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    for seed in 1..=4 {
+        let handle = fs
+            .lock()
+            .open_file(&format!("file{seed}"), FSChunker::new(4096), OpenMode::Read)
+            .unwrap();
+        assert_eq!(
+            fs.read_file_complete(&handle).unwrap(),
+            random_data(3 * MB, seed)
+        );
+    }
+}
+
+#[test]
+fn file_is_read_while_written() {
+    let fs = FileSystem::new_cdc_only(HashMap::default(), Sha256Hasher::default());
+    let fs = SharedFileSystem::new(fs);
+    let expected = random_data(4 * MB, 7);
+
+    let mut handle = fs
+        .lock()
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    let mut reader = fs
+        .lock()
+        .open_file("file", FSChunker::new(4096), OpenMode::Read)
+        .unwrap();
+
+    let writer = {
+        let fs = fs.clone();
+        let expected = expected.clone();
+        thread::spawn(move || {
+            for part in expected.chunks(10_000) {
+                fs.write_to_file(&mut handle, part).unwrap();
+            }
+            fs.lock().close_file(handle).unwrap();
+        })
+    };
+
+    // every read returns a prefix of the data that was stored by the time of the read
+    let mut read = vec![];
+    while read.len() < expected.len() {
+        read.extend(fs.read_from_file(&mut reader).unwrap());
+        assert_eq!(read, expected[..read.len()]);
+    }
+    writer.join().unwrap();
+    assert_eq!(read, expected);
+}
+
+#[test]
+fn background_scrubber_runs_while_files_are_written() {
+    let fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<_, Vec<u8>>>::default(),
+        Box::new(SubChunkScrubber::new(
+            FSChunker::new(1024),
+            Sha256Hasher::default(),
+        )),
+        Sha256Hasher::default(),
+    );
+    let fs = SharedFileSystem::new(fs);
+    let scrubber = fs.spawn_scrubber(ScrubPolicy {
+        idle_time: Duration::from_millis(50),
+        dirty_bytes: 256 * 1024,
+        budget: ScrubBudget::Bytes(64 * 1024),
+        poll_interval: Duration::from_millis(1),
+    });
+
+    let writers = (1..=2)
+        .map(|seed| {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let mut handle = fs
+                    .lock()
+                    .create_file(format!("file{seed}"), FSChunker::new(4096), true)
+                    .unwrap();
+                for part in random_data(3 * MB, seed).chunks(100_000) {
+                    fs.write_to_file(&mut handle, part).unwrap();
+                }
+                fs.lock().close_file(handle).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    // once the writes stop, the file system becomes idle, and the rest of the chunks is scrubbed
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while fs.lock().pending_scrub_data() > 0 {
+        assert!(Instant::now() < deadline, "chunks were not scrubbed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let measurements = scrubber.stop().unwrap();
+    assert_eq!(measurements.processed_data, 2 * 3 * MB);
+    assert_eq!(measurements.pending_chunks, 0);
+    assert_eq!(fs.lock().stats().unwrap().unique_bytes, 0);
+
+    for seed in 1..=2 {
+        let handle = fs
+            .lock()
+            .open_file(&format!("file{seed}"), FSChunker::new(4096), OpenMode::Read)
+            .unwrap();
+        assert_eq!(
+            fs.read_file_complete(&handle).unwrap(),
+            random_data(3 * MB, seed)
+        );
+    }
+}
+
+#[test]
+fn idle_background_scrubber_does_not_grow_journal() {
+    let path = std::env::temp_dir().join(format!("chunkfs-shared-idle-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    let metadata = path.join("metadata");
+    let journal = path.join("metadata.journal");
+
+    let mut fs = FileSystem::new(
+        HashMap::default(),
+        Box::<HashMap<_, Vec<u8>>>::default(),
+        Box::new(SubChunkScrubber::new(
+            FSChunker::new(1024),
+            Sha256Hasher::default(),
+        )),
+        Sha256Hasher::default(),
+    );
+    fs.save_metadata(&metadata).unwrap();
+    let fs = SharedFileSystem::new(fs);
+    let scrubber = fs.spawn_scrubber(ScrubPolicy {
+        idle_time: Duration::ZERO,
+        poll_interval: Duration::from_millis(1),
+        ..Default::default()
+    });
+
+    let mut handle = fs
+        .lock()
+        .create_file("file".to_string(), FSChunker::new(4096), true)
+        .unwrap();
+    fs.write_to_file(&mut handle, &random_data(MB, 1)).unwrap();
+    fs.lock().close_file(handle).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while fs.lock().pending_scrub_data() > 0 {
+        assert!(Instant::now() < deadline, "chunks were not scrubbed");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // nothing is recorded while there is nothing to scrub
+    let len = std::fs::metadata(&journal).unwrap().len();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(std::fs::metadata(&journal).unwrap().len(), len);
+    assert_eq!(scrubber.stop().unwrap().processed_data, MB);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}